@@ -0,0 +1,133 @@
+//! Step throughput and per-phase times of each backend over agent counts and
+//! world sizes, run with `cargo bench` or `cargo bench --features opencl`
+
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use srane::{
+    config::{Backend, Settings},
+    simulation::StepTimings,
+    Simulation,
+};
+
+/// Agent counts and square world sizes measured
+const CASES: [(u32, u32); 4] = [(6000, 512), (60000, 512), (60000, 1024), (300000, 2048)];
+/// Steps run before measuring
+const WARM_UP_STEPS: u64 = 20;
+
+/// Backends that can run here, OpenCL only with the feature and a device
+fn backends() -> Vec<Backend> {
+    let backends = vec![Backend::Cpu, Backend::CpuParallel];
+    #[cfg(feature = "opencl")]
+    let backends = if srane::gpu::devices().is_empty() {
+        backends
+    } else {
+        [backends, vec![Backend::OpenCl]].concat()
+    };
+    backends
+}
+
+/// Simulation warmed up on `backend` so trails have built up
+fn warm_simulation(agent_n: u32, size: u32, backend: Backend) -> Simulation {
+    let mut simulation = Simulation::new(Settings {
+        agent_n,
+        size_x: size,
+        size_y: size,
+        ..Settings::default()
+    });
+    run(&mut simulation, backend, WARM_UP_STEPS);
+    simulation
+}
+
+/// Run `n` steps on `backend`, returning the summed phase times
+fn run(simulation: &mut Simulation, backend: Backend, n: u64) -> StepTimings {
+    let mut timings = StepTimings::default();
+    match backend {
+        Backend::Cpu | Backend::CpuParallel => {
+            for _ in 0..n {
+                if backend == Backend::Cpu {
+                    simulation.step();
+                } else {
+                    simulation.step_parallel();
+                }
+                timings += *simulation.timings();
+            }
+        }
+        #[cfg(feature = "opencl")]
+        Backend::OpenCl => {
+            simulation
+                .step_gpu_n(n as usize)
+                .expect("OpenCL step failed");
+            timings += *simulation.timings() * n as u32;
+        }
+        #[cfg(not(feature = "opencl"))]
+        Backend::OpenCl => unreachable!(),
+    }
+    timings
+}
+
+/// Whole steps per second, including the OpenCL transfers
+fn steps(c: &mut Criterion) {
+    let mut group = c.benchmark_group("step");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(1));
+    for backend in backends() {
+        for (agent_n, size) in CASES {
+            // Created on first use so filtered out cases cost nothing
+            let mut simulation = None;
+            group.bench_function(
+                BenchmarkId::new(backend.to_string(), format!("{agent_n}@{size}")),
+                |b| {
+                    let simulation =
+                        simulation.get_or_insert_with(|| warm_simulation(agent_n, size, backend));
+                    b.iter_custom(|iters| {
+                        let start = Instant::now();
+                        run(simulation, backend, iters);
+                        start.elapsed()
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+/// Time of each phase, as measured by the simulation itself, but the
+/// lifecycle which is off here. Every backend decays the trail in the same
+/// pass over it as diffusion, so `diffuse_decay` times both and decay has no
+/// time of its own
+fn phases(c: &mut Criterion) {
+    let mut group = c.benchmark_group("phase");
+    group.sample_size(10);
+    for backend in backends() {
+        for (agent_n, size) in CASES {
+            let mut simulation = None;
+            for (phase, (name, _)) in StepTimings::default()
+                .phases()
+                .into_iter()
+                .enumerate()
+                .filter(|(_, (name, _))| *name != "lifecycle")
+            {
+                group.bench_function(
+                    BenchmarkId::new(format!("{backend}/{name}"), format!("{agent_n}@{size}")),
+                    |b| {
+                        let simulation = simulation.get_or_insert_with(|| {
+                            #[cfg_attr(not(feature = "opencl"), allow(unused_mut))]
+                            let mut simulation = warm_simulation(agent_n, size, backend);
+                            #[cfg(feature = "opencl")]
+                            simulation.set_gpu_timed(true);
+                            simulation
+                        });
+                        b.iter_custom(|iters| -> Duration {
+                            run(simulation, backend, iters).phases()[phase].1
+                        })
+                    },
+                );
+            }
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, steps, phases);
+criterion_main!(benches);
@@ -0,0 +1,128 @@
+//! Headless runner: steps a simulation and writes the trail map as PNG frames
+
+use std::{fs, path::PathBuf, process::ExitCode, time::Instant};
+
+use clap::Parser;
+use srane::{
+    cli::{BackendArgs, SettingsArgs},
+    config::Backend,
+    render, Simulation,
+};
+#[cfg(feature = "opencl")]
+use tracing::warn;
+use tracing::{error, info};
+
+/// Run the agent simulation without a window and save frames as PNG
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct BatchCli {
+    #[command(flatten)]
+    settings: SettingsArgs,
+    /// Number of steps to run, at least one
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    steps: u64,
+    /// Write a frame every K steps, defaults to only the last step
+    #[arg(long)]
+    every: Option<u64>,
+    /// Directory receiving the frames
+    #[arg(long, default_value = "frames")]
+    output: PathBuf,
+    #[command(flatten)]
+    backend: BackendArgs,
+}
+
+fn main() -> ExitCode {
+    tracing_subscriber::fmt::init();
+
+    let cli = BatchCli::parse();
+    let settings = match cli.settings.settings() {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = fs::create_dir_all(&cli.output) {
+        error!("cannot create {}: {e}", cli.output.display());
+        return ExitCode::FAILURE;
+    }
+    // Only OpenCL falls back to another backend
+    #[cfg_attr(not(feature = "opencl"), allow(unused_mut))]
+    let mut backend = cli.backend.backend();
+    let every = cli.every.unwrap_or(cli.steps).max(1);
+
+    let mut simulation = Simulation::new(settings);
+    simulation.set_precision(cli.backend.precision);
+    if let Err(e) = cli.settings.load_layers(&mut simulation) {
+        error!("{e}");
+        return ExitCode::FAILURE;
+    }
+    let mut frames = 0;
+    let start = Instant::now();
+    let mut step = 0;
+    while step < cli.steps {
+        // Run up to the next frame in one go, the OpenCL backend reads back
+        // the state only then
+        let n = (every - step % every).min(cli.steps - step);
+        match backend {
+            Backend::Cpu => simulation.step_n(n as usize),
+            Backend::CpuParallel => {
+                for _ in 0..n {
+                    simulation.step_parallel();
+                }
+            }
+            #[cfg(feature = "opencl")]
+            Backend::OpenCl => {
+                if let Err(e) = simulation.step_gpu_n(n as usize) {
+                    warn!("{e}, falling back to the cpu backend");
+                    backend = Backend::Cpu;
+                    simulation.step_n(n as usize);
+                }
+            }
+            #[cfg(not(feature = "opencl"))]
+            Backend::OpenCl => unreachable!(),
+        }
+        step += n;
+
+        let path = cli.output.join(format!("frame_{step:06}.png"));
+        let (size_x, size_y) = simulation.size();
+        if let Err(e) = image::save_buffer(
+            &path,
+            &render::rgb_image(&simulation),
+            size_x,
+            size_y,
+            image::ColorType::Rgb8,
+        ) {
+            error!("cannot write {}: {e}", path.display());
+            return ExitCode::FAILURE;
+        }
+        info!("wrote {}", path.display());
+        frames += 1;
+    }
+    let elapsed = start.elapsed();
+
+    let settings = simulation.settings();
+    let (mut sum, mut peak) = (0.0, 0_f64);
+    for &value in simulation.trail_maps().iter().flatten() {
+        sum += value;
+        peak = peak.max(value);
+    }
+    println!(
+        "{} steps on {backend} in {:.2?} ({:.1} steps/s), {frames} frames in {}",
+        cli.steps,
+        elapsed,
+        cli.steps as f64 / elapsed.as_secs_f64(),
+        cli.output.display()
+    );
+    println!(
+        "world {}x{}, {} agents of {} species, seed {}, trail mean {:.3} max {:.3}",
+        settings.size_x,
+        settings.size_y,
+        settings.agent_n,
+        settings.species_n,
+        settings.seed,
+        sum / (settings.size_x * settings.size_y * settings.species_n as u32) as f64,
+        peak
+    );
+    ExitCode::SUCCESS
+}
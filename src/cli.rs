@@ -0,0 +1,104 @@
+//! Command line arguments shared by the binaries
+
+use std::path::PathBuf;
+
+use clap::Args;
+
+use tracing::warn;
+
+use crate::{
+    config::{Backend, Precision, Settings, Spawn, MAX_SEED},
+    preset::{Preset, PresetError},
+    Simulation,
+};
+
+/// Simulation settings shared by the GUI and batch binaries
+#[derive(Args, Debug)]
+pub struct SettingsArgs {
+    /// Preset file to start from (TOML, or JSON with a .json extension)
+    pub preset: Option<PathBuf>,
+    /// Random seed up to 2^63 - 1, overrides the preset one
+    #[arg(long, value_parser = clap::value_parser!(u64).range(..=MAX_SEED))]
+    pub seed: Option<u64>,
+    /// World width, overrides the preset one
+    #[arg(long)]
+    pub size_x: Option<u32>,
+    /// World height, overrides the preset one
+    #[arg(long)]
+    pub size_y: Option<u32>,
+    /// Number of agents, overrides the preset one
+    #[arg(long)]
+    pub agents: Option<u32>,
+    /// Initial agent placement: random, circle or star
+    #[arg(long)]
+    pub spawn: Option<Spawn>,
+    /// Greyscale nutrient image, white emits food_strength per step
+    #[arg(long)]
+    pub food: Option<PathBuf>,
+    /// Obstacle image, dark cells are walls
+    #[arg(long)]
+    pub walls: Option<PathBuf>,
+}
+
+/// Step backend and precision shared by the GUI and batch binaries
+#[derive(Args, Debug)]
+pub struct BackendArgs {
+    /// Step backend: cpu, cpu-parallel or opencl
+    #[arg(long, default_value = "cpu")]
+    pub backend: Backend,
+    /// Float type of agents and trails on every backend: f32 or f64
+    #[arg(long, default_value = "f64")]
+    pub precision: Precision,
+}
+
+impl BackendArgs {
+    /// Backend asked for, the CPU one when OpenCL is not built in
+    pub fn backend(&self) -> Backend {
+        if self.backend == Backend::OpenCl && !cfg!(feature = "opencl") {
+            warn!("built without the opencl feature, using the cpu backend");
+            return Backend::Cpu;
+        }
+        self.backend
+    }
+}
+
+impl SettingsArgs {
+    /// Settings from the preset file, or defaults, with command line overrides
+    /// applied and clamped to the supported range
+    pub fn settings(&self) -> Result<Settings, PresetError> {
+        let mut settings = match &self.preset {
+            Some(path) => Preset::load(path)?.settings,
+            None => Settings::default(),
+        };
+        if let Some(seed) = self.seed {
+            settings.seed = seed;
+        }
+        if let Some(size_x) = self.size_x {
+            settings.size_x = size_x;
+        }
+        if let Some(size_y) = self.size_y {
+            settings.size_y = size_y;
+        }
+        if let Some(agents) = self.agents {
+            settings.agent_n = agents;
+        }
+        if let Some(spawn) = self.spawn {
+            settings.spawn = spawn;
+        }
+        settings.clamp_to_max();
+        Ok(settings)
+    }
+
+    /// Load the world layers given on the command line
+    pub fn load_layers(&self, simulation: &mut Simulation) -> Result<(), image::ImageError> {
+        if let Some(path) = &self.food {
+            simulation.load_food(path)?;
+        }
+        if let Some(path) = &self.walls {
+            simulation.load_walls(path)?;
+            // Move agents spawned inside the new walls
+            simulation.reset();
+        }
+        Ok(())
+    }
+}
@@ -1,19 +1,44 @@
-/// Max settings
-pub const MAX_SIZE_X: u32 = 1024;
-pub const MAX_SIZE_Y: u32 = 1024;
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// Max settings, the world dimensions bounding the trail buffers to 1 GiB
+/// for four species
+pub const MAX_SIZE_X: u32 = 4096;
+pub const MAX_SIZE_Y: u32 = 4096;
 pub const MAX_AGENT_N: u32 = 300000;
+/// Largest seed a TOML preset can hold, its integers being signed
+pub const MAX_SEED: u64 = i64::MAX as u64;
 pub const MAX_AGENT_SPEED: f64 = 3_f64;
 pub const MAX_AGENT_TURN: f64 = 360_f64;
 pub const MAX_SENSOR_ANGLE: f64 = 360_f64;
 pub const MAX_SENSOR_DISTANCE: f64 = 10_f64;
 pub const MAX_SENSOR_SIZE: u8 = 5;
+pub const MAX_FAN_SENSORS: u8 = 9;
+pub const MAX_STEERING_GAIN: f64 = 10_f64;
+pub const MAX_SOFTMAX_TEMPERATURE: f64 = 100_f64;
 pub const MAX_TRAIL_WEIGHT: f64 = 500_f64;
 pub const MAX_TRAIL_DECAY: f64 = 100_f64;
 pub const MAX_TRAIL_DIFFUSE: f64 = 1_f64;
+pub const MAX_TRAIL_DECAY_RATE: f64 = 1_f64;
+pub const MAX_TRAIL_HALF_LIFE: f64 = 1000_f64;
+pub const MAX_DEPOSIT_CAP: f64 = 5000_f64;
+pub const MAX_SPECIES: usize = 4;
+pub const MAX_ATTRACTION: f64 = 1_f64;
+pub const MAX_FOOD_RADIUS: f64 = 64_f64;
+pub const MAX_FOOD_STRENGTH: f64 = 100_f64;
+pub const MAX_WALL_RADIUS: f64 = 64_f64;
+pub const MAX_KERNEL_RADIUS: usize = 6;
+pub const MAX_KERNEL_SIGMA: f64 = 2_f64;
+pub const MAX_MOVE_COST: f64 = 1_f64;
+pub const MAX_FOOD_GAIN: f64 = 1_f64;
+pub const MAX_TRAIL_GAIN: f64 = 0.01;
+pub const MAX_ENERGY: f64 = 100_f64;
 
 /// Default settings
 const SIZE_X: u32 = 512;
 const SIZE_Y: u32 = 512;
+const SEED: u64 = 0;
 const AGENT_N: u32 = 6000;
 const AGENT_SPEED: f64 = 1_f64;
 const AGENT_TURN: f64 = 35_f64;
@@ -24,42 +49,625 @@ const SENSOR_SIZE: u8 = 1;
 const TRAIL_WEIGHT: f64 = 255_f64;
 const TRAIL_DECAY: f64 = 1.8;
 const TRAIL_DIFFUSE: f64 = 0.07;
+const TRAIL_DECAY_RATE: f64 = 0.05;
+const TRAIL_HALF_LIFE: f64 = 20_f64;
+const DEPOSIT_CAP: f64 = 255_f64;
+const SPECIES_N: u8 = 1;
+const ATTRACTION: f64 = 1_f64;
+const FOOD_RADIUS: f64 = 8_f64;
+const FOOD_STRENGTH: f64 = 20_f64;
+const WALL_RADIUS: f64 = 4_f64;
+const MOVE_COST: f64 = 0.05;
+const FOOD_GAIN: f64 = 0.01;
+const TRAIL_GAIN: f64 = 0.0002;
+const DEATH_ENERGY: f64 = 0_f64;
+const DIVISION_ENERGY: f64 = 10_f64;
+const MAX_AGENTS: u32 = 100000;
+const SPECIES_COLORS: [[u8; 3]; MAX_SPECIES] = [
+    [255, 255, 255],
+    [255, 64, 64],
+    [64, 255, 64],
+    [64, 128, 255],
+];
 
-pub struct Settings {
-    /// Simulations settings
-    pub size_x: u32,
-    pub size_y: u32,
+/// Initial placement of agents
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Spawn {
+    /// Uniform over the world with random heading
+    #[default]
+    Random = 0,
+    /// Uniform in a disc of `spawn_radius`, heading to the center
+    Circle = 1,
+    /// All at the center with random heading
+    Star = 2,
+}
+
+impl FromStr for Spawn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "random" => Ok(Spawn::Random),
+            "circle" => Ok(Spawn::Circle),
+            "star" => Ok(Spawn::Star),
+            _ => Err(format!(
+                "unknown spawn `{s}`, expected random, circle or star"
+            )),
+        }
+    }
+}
+
+/// What happens to agents, sensors and diffusion at the world edges
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BoundaryMode {
+    /// Agents stop at the edge with a random heading, sampling is clamped
+    #[default]
+    Clamp = 0,
+    /// Torus, opposite edges are connected
+    Wrap = 1,
+    /// Agents bounce like a mirror, sampling is mirrored
+    Reflect = 2,
+    /// Agents leaving are respawned, outside cells read as empty
+    Respawn = 3,
+}
+
+impl BoundaryMode {
+    pub const ALL: [BoundaryMode; 4] = [
+        BoundaryMode::Clamp,
+        BoundaryMode::Wrap,
+        BoundaryMode::Reflect,
+        BoundaryMode::Respawn,
+    ];
+}
+
+impl fmt::Display for BoundaryMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BoundaryMode::Clamp => write!(f, "clamp"),
+            BoundaryMode::Wrap => write!(f, "wrap"),
+            BoundaryMode::Reflect => write!(f, "reflect"),
+            BoundaryMode::Respawn => write!(f, "respawn"),
+        }
+    }
+}
+
+/// How trail fades each step, after diffusion
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DecayMode {
+    /// Subtract `trail_decay`, clamped to zero
+    #[default]
+    Linear,
+    /// Multiply by `1 - trail_decay_rate`
+    Multiplicative,
+    /// Halve every `trail_half_life` steps
+    Exponential,
+    /// Multiply by `1 - trail_decay_rate`, then subtract `trail_decay`
+    Combined,
+}
+
+impl DecayMode {
+    pub const ALL: [DecayMode; 4] = [
+        DecayMode::Linear,
+        DecayMode::Multiplicative,
+        DecayMode::Exponential,
+        DecayMode::Combined,
+    ];
+}
+
+impl fmt::Display for DecayMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecayMode::Linear => write!(f, "linear"),
+            DecayMode::Multiplicative => write!(f, "multiplicative"),
+            DecayMode::Exponential => write!(f, "exponential"),
+            DecayMode::Combined => write!(f, "combined"),
+        }
+    }
+}
+
+/// How agents share the cells of the world
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MovementMode {
+    /// Any number of agents per cell
+    #[default]
+    Free,
+    /// One agent per cell, agents finding their destination taken stay, turn
+    /// to a random heading and skip their deposit, as in Jones 2010
+    Exclusive,
+}
+
+impl MovementMode {
+    pub const ALL: [MovementMode; 2] = [MovementMode::Free, MovementMode::Exclusive];
+}
+
+impl fmt::Display for MovementMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovementMode::Free => write!(f, "free"),
+            MovementMode::Exclusive => write!(f, "exclusive"),
+        }
+    }
+}
+
+/// How an agent trail weight combines with the trail already in its cell
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DepositMode {
+    /// Replace the trail by the weight
+    #[default]
+    Overwrite,
+    /// Add the weight, cells crossed by many agents build up
+    Additive,
+    /// Add the weight, up to `deposit_cap`
+    Capped,
+    /// Keep the larger of the trail and the weight
+    Max,
+}
+
+impl DepositMode {
+    pub const ALL: [DepositMode; 4] = [
+        DepositMode::Overwrite,
+        DepositMode::Additive,
+        DepositMode::Capped,
+        DepositMode::Max,
+    ];
+}
+
+impl fmt::Display for DepositMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DepositMode::Overwrite => write!(f, "overwrite"),
+            DepositMode::Additive => write!(f, "additive"),
+            DepositMode::Capped => write!(f, "capped"),
+            DepositMode::Max => write!(f, "max"),
+        }
+    }
+}
+
+/// Where the simulation steps are computed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Backend {
+    #[default]
+    Cpu,
+    /// Agents and rows split over all CPU threads
+    CpuParallel,
+    OpenCl,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "cpu" => Ok(Backend::Cpu),
+            "cpu-parallel" => Ok(Backend::CpuParallel),
+            "opencl" => Ok(Backend::OpenCl),
+            _ => Err(format!(
+                "unknown backend `{s}`, expected cpu, cpu-parallel or opencl"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Cpu => write!(f, "cpu"),
+            Backend::CpuParallel => write!(f, "cpu-parallel"),
+            Backend::OpenCl => write!(f, "opencl"),
+        }
+    }
+}
+
+/// Float type agents and trail channels are kept in, on the host as in the
+/// OpenCL buffers and kernels
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Precision {
+    /// `f32`, for devices without `cl_khr_fp64` or for speed
+    Single,
+    #[default]
+    Double,
+}
+
+impl Precision {
+    pub const ALL: [Precision; 2] = [Precision::Single, Precision::Double];
+}
+
+impl FromStr for Precision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "f32" | "single" => Ok(Precision::Single),
+            "f64" | "double" => Ok(Precision::Double),
+            _ => Err(format!("unknown precision `{s}`, expected f32 or f64")),
+        }
+    }
+}
+
+impl fmt::Display for Precision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Precision::Single => write!(f, "f32"),
+            Precision::Double => write!(f, "f64"),
+        }
+    }
+}
+
+/// Weights spreading trail to the neighbouring cells when diffusing
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", tag = "kind")]
+pub enum DiffusionKernel {
+    /// Mean of the 3×3 neighbourhood
+    #[default]
+    Box3,
+    /// Mean of the 5×5 neighbourhood
+    Box5,
+    /// Isotropic Gaussian, truncated at 3 `sigma`
+    Gaussian { sigma: f64 },
+    /// Gaussian stretched along `angle` degrees, streaks trails in one direction
+    Anisotropic {
+        sigma_along: f64,
+        sigma_across: f64,
+        angle: f64,
+    },
+    /// Square matrix of odd side row by row, a 3×3 box when not square
+    Custom { weights: Vec<f64> },
+}
+
+impl DiffusionKernel {
+    /// One kernel of each kind with default parameters
+    pub fn defaults() -> [DiffusionKernel; 5] {
+        [
+            DiffusionKernel::Box3,
+            DiffusionKernel::Box5,
+            DiffusionKernel::Gaussian { sigma: 1.0 },
+            DiffusionKernel::Anisotropic {
+                sigma_along: 2.0,
+                sigma_across: 0.5,
+                angle: 0.0,
+            },
+            DiffusionKernel::Custom {
+                weights: vec![1.0, 2.0, 1.0, 2.0, 4.0, 2.0, 1.0, 2.0, 1.0],
+            },
+        ]
+    }
+
+    /// Weights matrix of this kernel
+    pub fn stencil(&self) -> Stencil {
+        let gaussian = |sigma_along: f64, sigma_across: f64, angle: f64| {
+            let radius =
+                ((3.0 * sigma_along.max(sigma_across)).ceil() as usize).clamp(1, MAX_KERNEL_RADIUS);
+            let (sin, cos) = angle.to_radians().sin_cos();
+            Stencil::from_fn(radius, |dx, dy| {
+                let along = dx * cos + dy * sin;
+                let across = dy * cos - dx * sin;
+                (-along * along / (2.0 * sigma_along * sigma_along)
+                    - across * across / (2.0 * sigma_across * sigma_across))
+                    .exp()
+            })
+        };
+        match self {
+            DiffusionKernel::Box3 => Stencil::from_fn(1, |_, _| 1.0),
+            DiffusionKernel::Box5 => Stencil::from_fn(2, |_, _| 1.0),
+            DiffusionKernel::Gaussian { sigma } => gaussian(*sigma, *sigma, 0.0),
+            DiffusionKernel::Anisotropic {
+                sigma_along,
+                sigma_across,
+                angle,
+            } => gaussian(*sigma_along, *sigma_across, *angle),
+            DiffusionKernel::Custom { weights } => {
+                let side = (weights.len() as f64).sqrt() as usize;
+                if side % 2 != 1 || side * side != weights.len() || side > 2 * MAX_KERNEL_RADIUS + 1
+                {
+                    return DiffusionKernel::Box3.stencil();
+                }
+                Stencil {
+                    radius: side / 2,
+                    weights: weights.clone(),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for DiffusionKernel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffusionKernel::Box3 => write!(f, "box3"),
+            DiffusionKernel::Box5 => write!(f, "box5"),
+            DiffusionKernel::Gaussian { .. } => write!(f, "gaussian"),
+            DiffusionKernel::Anisotropic { .. } => write!(f, "anisotropic"),
+            DiffusionKernel::Custom { .. } => write!(f, "custom"),
+        }
+    }
+}
+
+/// Weights of a diffusion kernel, a square of side `2 * radius + 1` row by row
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stencil {
+    pub radius: usize,
+    pub weights: Vec<f64>,
+}
+
+impl Stencil {
+    /// Stencil with the weight `weight(dx, dy)` at each offset
+    fn from_fn(radius: usize, weight: impl Fn(f64, f64) -> f64) -> Stencil {
+        let radius_i = radius as isize;
+        let weights = (-radius_i..=radius_i)
+            .flat_map(|dy| (-radius_i..=radius_i).map(move |dx| (dx, dy)))
+            .map(|(dx, dy)| weight(dx as f64, dy as f64))
+            .collect();
+        Stencil { radius, weights }
+    }
+
+    pub fn side(&self) -> usize {
+        2 * self.radius + 1
+    }
+
+    /// Weight at offset `(dx, dy)` from the center
+    pub fn weight(&self, dx: isize, dy: isize) -> f64 {
+        let radius = self.radius as isize;
+        self.weights[((dy + radius) * self.side() as isize + dx + radius) as usize]
+    }
+}
+
+/// How an agent turns from the trail its sensors read, sensors are spread
+/// evenly from `-sensor_angle` (right) to `sensor_angle` (left)
+#[derive(Clone, Copy, Debug, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", tag = "kind")]
+pub enum SteeringRule {
+    /// Three sensors, turn by a random share of `agent_turn` toward the
+    /// stronger side, either way when forward is the weakest
+    #[default]
+    Random,
+    /// Three sensors, turn by exactly `agent_turn` toward the stronger side,
+    /// a random side when forward is the weakest, as in Jones 2010
+    Fixed,
+    /// Three sensors, turn by `agent_turn` times `gain` times the difference
+    /// between left and right over the sum of all, up to `agent_turn`
+    Gradient { gain: f64 },
+    /// Three sensors, pick one with a probability growing as
+    /// `exp(trail / temperature)` and turn by `agent_turn` toward it
+    Softmax { temperature: f64 },
+    /// `sensors` sensors, turn toward the strongest by at most `agent_turn`,
+    /// ties go to the one nearest forward
+    Fan { sensors: u8 },
+}
+
+impl SteeringRule {
+    /// One rule of each kind with default parameters
+    pub fn defaults() -> [SteeringRule; 5] {
+        [
+            SteeringRule::Random,
+            SteeringRule::Fixed,
+            SteeringRule::Gradient { gain: 2.0 },
+            SteeringRule::Softmax { temperature: 20.0 },
+            SteeringRule::Fan { sensors: 5 },
+        ]
+    }
+
+    /// Number of sensors read by this rule
+    pub fn sensors(&self) -> usize {
+        match self {
+            SteeringRule::Fan { sensors } => (*sensors).clamp(2, MAX_FAN_SENSORS) as usize,
+            _ => 3,
+        }
+    }
+}
+
+impl fmt::Display for SteeringRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SteeringRule::Random => write!(f, "random"),
+            SteeringRule::Fixed => write!(f, "fixed"),
+            SteeringRule::Gradient { .. } => write!(f, "gradient"),
+            SteeringRule::Softmax { .. } => write!(f, "softmax"),
+            SteeringRule::Fan { .. } => write!(f, "fan"),
+        }
+    }
+}
+
+/// Parameters of one agent species and its trail channel
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Species {
     /// Agents settings
-    pub agent_n: u32,
     pub agent_speed: f64,
     pub agent_turn: f64,
-    /// Spawn Settings
-    pub spawn_radius: f64,
     /// Sensor Settings
     pub sensor_angle: f64,
     pub sensor_distance: f64,
     pub sensor_size: u8,
+    pub steering: SteeringRule,
     /// Trail Settings
     pub trail_weight: f64,
+    pub color: [u8; 3],
+    /// Weight of each species trail when sensing, positive follows and negative avoids
+    pub attraction: [f64; MAX_SPECIES],
+}
+
+impl Species {
+    /// Default species number `index`, following its own trail and avoiding others
+    pub fn new(index: usize) -> Species {
+        let mut attraction = [-ATTRACTION; MAX_SPECIES];
+        attraction[index] = ATTRACTION;
+        Species {
+            agent_speed: AGENT_SPEED,
+            agent_turn: AGENT_TURN,
+            sensor_angle: SENSOR_ANGLE,
+            sensor_distance: SENSOR_DISTANCE,
+            sensor_size: SENSOR_SIZE,
+            steering: SteeringRule::default(),
+            trail_weight: TRAIL_WEIGHT,
+            color: SPECIES_COLORS[index],
+            attraction,
+        }
+    }
+}
+
+impl Default for Species {
+    fn default() -> Species {
+        Species::new(0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Simulations settings
+    pub size_x: u32,
+    pub size_y: u32,
+    pub seed: u64,
+    pub boundary: BoundaryMode,
+    /// Agents settings
+    pub agent_n: u32,
+    pub movement: MovementMode,
+    /// Spawn Settings
+    pub spawn: Spawn,
+    pub spawn_radius: f64,
+    /// Trail Settings
+    pub deposit: DepositMode,
+    pub deposit_cap: f64,
+    /// Deposit over the cells an agent overlaps and sense between cells
+    /// instead of snapping to the nearest one
+    pub bilinear: bool,
     pub trail_decay: f64,
     pub trail_diffuse: f64,
+    pub diffusion: DiffusionKernel,
+    pub decay_mode: DecayMode,
+    pub trail_decay_rate: f64,
+    pub trail_half_life: f64,
+    /// Food Settings, radius and emission per step of new food sources
+    pub food_radius: f64,
+    pub food_strength: f64,
+    /// Obstacle Settings, radius of the wall brush
+    pub wall_radius: f64,
+    /// Lifecycle Settings, agents spend `move_cost` energy per unit of
+    /// `agent_speed` and gain `food_gain` per unit of nutrient and
+    /// `trail_gain` per unit of their species trail in their cell each step.
+    /// They die below `death_energy` and divide above `division_energy`
+    /// while fewer than `max_agents` are alive, `agent_n` follows the
+    /// population
+    pub lifecycle: bool,
+    pub move_cost: f64,
+    pub food_gain: f64,
+    pub trail_gain: f64,
+    pub death_energy: f64,
+    pub division_energy: f64,
+    pub max_agents: u32,
+    /// Species settings, only the first `species_n` are simulated
+    pub species_n: u8,
+    pub species: [Species; MAX_SPECIES],
 }
 
 impl Settings {
-    pub fn default_agents(&mut self) {
+    /// Settings with a single species
+    pub fn single(species: Species) -> Settings {
+        let mut settings = Settings::default();
+        settings.species[0] = species;
+        settings
+    }
+
+    /// Species of the agent number `index` when spawning
+    pub fn species_of(&self, index: usize) -> u32 {
+        (index % self.species_n.max(1) as usize) as u32
+    }
+
+    pub fn default_agents(&mut self, species: usize) {
         self.agent_n = AGENT_N;
-        self.agent_speed = AGENT_SPEED;
-        self.agent_turn = AGENT_TURN;
+        self.movement = MovementMode::default();
+        self.species[species].agent_speed = AGENT_SPEED;
+        self.species[species].agent_turn = AGENT_TURN;
     }
-    pub fn default_sensor(&mut self) {
-        self.sensor_angle = SENSOR_ANGLE;
-        self.sensor_distance = SENSOR_DISTANCE;
-        self.sensor_size = SENSOR_SIZE;
+    pub fn default_sensor(&mut self, species: usize) {
+        self.species[species].sensor_angle = SENSOR_ANGLE;
+        self.species[species].sensor_distance = SENSOR_DISTANCE;
+        self.species[species].sensor_size = SENSOR_SIZE;
+        self.species[species].steering = SteeringRule::default();
     }
-    pub fn default_trail(&mut self) {
-        self.trail_weight = TRAIL_WEIGHT;
+    pub fn default_trail(&mut self, species: usize) {
+        self.species[species].trail_weight = TRAIL_WEIGHT;
+        self.deposit = DepositMode::default();
+        self.deposit_cap = DEPOSIT_CAP;
+        self.bilinear = false;
         self.trail_decay = TRAIL_DECAY;
         self.trail_diffuse = TRAIL_DIFFUSE;
+        self.diffusion = DiffusionKernel::default();
+        self.decay_mode = DecayMode::default();
+        self.trail_decay_rate = TRAIL_DECAY_RATE;
+        self.trail_half_life = TRAIL_HALF_LIFE;
+    }
+    pub fn default_food(&mut self) {
+        self.food_radius = FOOD_RADIUS;
+        self.food_strength = FOOD_STRENGTH;
+    }
+    pub fn default_species(&mut self, species: usize) {
+        self.species[species] = Species::new(species);
+    }
+    pub fn default_lifecycle(&mut self) {
+        self.move_cost = MOVE_COST;
+        self.food_gain = FOOD_GAIN;
+        self.trail_gain = TRAIL_GAIN;
+        self.death_energy = DEATH_ENERGY;
+        self.division_energy = DIVISION_ENERGY;
+        self.max_agents = MAX_AGENTS;
+    }
+    /// Decay of `decay_mode` as a factor applied to the trail and an amount
+    /// subtracted after it
+    pub fn decay(&self) -> (f64, f64) {
+        match self.decay_mode {
+            DecayMode::Linear => (1.0, self.trail_decay),
+            DecayMode::Multiplicative => (1.0 - self.trail_decay_rate, 0.0),
+            DecayMode::Exponential => (0.5_f64.powf(1.0 / self.trail_half_life), 0.0),
+            DecayMode::Combined => (1.0 - self.trail_decay_rate, self.trail_decay),
+        }
+    }
+    /// Bring values from files or command line back in the supported range
+    pub fn clamp_to_max(&mut self) {
+        self.size_x = self.size_x.clamp(1, MAX_SIZE_X);
+        self.size_y = self.size_y.clamp(1, MAX_SIZE_Y);
+        self.seed = self.seed.min(MAX_SEED);
+        self.agent_n = self.agent_n.min(MAX_AGENT_N);
+        self.species_n = self.species_n.clamp(1, MAX_SPECIES as u8);
+        self.trail_decay_rate = self.trail_decay_rate.clamp(0.0, MAX_TRAIL_DECAY_RATE);
+        self.trail_half_life = self.trail_half_life.clamp(0.1, MAX_TRAIL_HALF_LIFE);
+        self.deposit_cap = self.deposit_cap.clamp(0.0, MAX_DEPOSIT_CAP);
+        self.max_agents = self.max_agents.clamp(1, MAX_AGENT_N);
+        for species in &mut self.species {
+            species.agent_speed = species.agent_speed.clamp(0.0, MAX_AGENT_SPEED);
+            species.agent_turn = species.agent_turn.clamp(0.0, MAX_AGENT_TURN);
+            species.sensor_angle = species.sensor_angle.clamp(0.0, MAX_SENSOR_ANGLE);
+            species.sensor_distance = species.sensor_distance.clamp(0.0, MAX_SENSOR_DISTANCE);
+            species.sensor_size = species.sensor_size.min(MAX_SENSOR_SIZE);
+            species.trail_weight = species.trail_weight.clamp(0.0, MAX_TRAIL_WEIGHT);
+            match &mut species.steering {
+                SteeringRule::Gradient { gain } => *gain = gain.clamp(0.0, MAX_STEERING_GAIN),
+                SteeringRule::Softmax { temperature } => {
+                    *temperature = temperature.clamp(0.1, MAX_SOFTMAX_TEMPERATURE);
+                }
+                SteeringRule::Fan { sensors } => *sensors = (*sensors).clamp(2, MAX_FAN_SENSORS),
+                _ => {}
+            }
+        }
+        match &mut self.diffusion {
+            DiffusionKernel::Gaussian { sigma } => {
+                *sigma = sigma.clamp(0.1, MAX_KERNEL_SIGMA);
+            }
+            DiffusionKernel::Anisotropic {
+                sigma_along,
+                sigma_across,
+                ..
+            } => {
+                *sigma_along = sigma_along.clamp(0.1, MAX_KERNEL_SIGMA);
+                *sigma_across = sigma_across.clamp(0.1, MAX_KERNEL_SIGMA);
+            }
+            _ => {}
+        }
     }
 }
 
@@ -68,16 +676,33 @@ impl Default for Settings {
         Settings {
             size_x: SIZE_X,
             size_y: SIZE_Y,
+            seed: SEED,
+            boundary: BoundaryMode::default(),
             agent_n: AGENT_N,
-            agent_speed: AGENT_SPEED,
-            agent_turn: AGENT_TURN,
+            movement: MovementMode::default(),
+            spawn: Spawn::default(),
             spawn_radius: SPAWN_RADIUS,
-            sensor_angle: SENSOR_ANGLE,
-            sensor_distance: SENSOR_DISTANCE,
-            sensor_size: SENSOR_SIZE,
-            trail_weight: TRAIL_WEIGHT,
+            deposit: DepositMode::default(),
+            deposit_cap: DEPOSIT_CAP,
+            bilinear: false,
             trail_decay: TRAIL_DECAY,
             trail_diffuse: TRAIL_DIFFUSE,
+            diffusion: DiffusionKernel::default(),
+            decay_mode: DecayMode::default(),
+            trail_decay_rate: TRAIL_DECAY_RATE,
+            trail_half_life: TRAIL_HALF_LIFE,
+            food_radius: FOOD_RADIUS,
+            food_strength: FOOD_STRENGTH,
+            wall_radius: WALL_RADIUS,
+            lifecycle: false,
+            move_cost: MOVE_COST,
+            food_gain: FOOD_GAIN,
+            trail_gain: TRAIL_GAIN,
+            death_energy: DEATH_ENERGY,
+            division_energy: DIVISION_ENERGY,
+            max_agents: MAX_AGENTS,
+            species_n: SPECIES_N,
+            species: std::array::from_fn(Species::new),
         }
     }
 }
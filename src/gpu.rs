@@ -1,210 +1,1253 @@
-use ocl::ProQue;
+use std::{ffi::CString, fmt, time::Instant};
+
+use ocl::{
+    core::{self, DeviceInfo, ProgramBuildInfo, ProgramBuildInfoResult},
+    Buffer, Context, Device, Kernel, OclPrm, Platform, ProQue, Program, Queue,
+};
+use tracing::warn;
 
 use crate::{
-    config::{Settings, MAX_SIZE_X, MAX_SIZE_Y},
-    simulation::{Agent, Agents, TrailMap},
+    config::{
+        MovementMode, Precision, Settings, SteeringRule, MAX_AGENT_N, MAX_FAN_SENSORS,
+        MAX_KERNEL_RADIUS, MAX_SPECIES,
+    },
+    layer::{NutrientMap, ObstacleMap},
+    simulation::{Agent, Agents, Real, StepTimings, TrailMap, SUBCELL_STEPS},
 };
 
-pub fn gpu_move(agents: &mut Agents, settings: &Settings) -> ocl::Result<()> {
-    let kernel = r#"
-
-        typedef struct s_agent {
-            double pos_x;
-            double pos_y;
-            double angle;
-        } agent;
-
-        __kernel void move(__global agent * source, double agent_speed, uint agent_n, uint size_x, uint size_y) {
-            if (get_global_id(0) < agent_n) {
-
-                one_agent->pos_x += cos(one_agent->angle) * agent_speed;
-                one_agent->pos_y += sin(one_agent->angle) * agent_speed;
-
-                // Check Collision
-                // TODO
-                if ( (one_agent->pos_x < (double)0) ||
-                     (one_agent->pos_x >= (double)size_x) ||
-                     (one_agent->pos_y < (double)0) ||
-                     (one_agent->pos_y >= (double)size_y)
-                    ) {
-                    // one_agent->pos_x = one_agent->pos_x.max(0_f64).min(size_x - 1);
-                    // one_agent->pos_y = one_agent->pos_y.max(0_f64).min(size_y - 1);
-                    // one_agent->angle = rng.gen::<f64>() * 2_f64 * PI;
+/// Parameters of one species packed for the kernels: speed, turn, sensor
+/// angle, sensor distance, sensor size, trail weight, steering rule, its
+/// parameter and attractions
+const SPECIES_STRIDE: usize = 8 + MAX_SPECIES;
+/// Attempts to respawn an agent in the world outside walls before giving up
+const SPAWN_TRIES: usize = 64;
+
+/// Agent layout, random generator and helpers shared by the kernels
+const COMMON_CL: &str = r#"
+    typedef struct s_agent {
+        real pos_x;
+        real pos_y;
+        real angle;
+        uint species;
+    } agent;
+
+    // xorshift64*, uniform in [0, 1) with as many bits as `real` holds
+    real rand_real(ulong * state) {
+        ulong x = *state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        *state = x;
+        return (real)((x * 0x2545F4914F6CDD1DUL) >> (64 - RAND_BITS)) / (real)(1UL << RAND_BITS);
+    }
+
+    bool inside(real x, real y, uint size_x, uint size_y) {
+        return x >= 0 && x < (real)size_x && y >= 0 && y < (real)size_y;
+    }
+
+    bool is_wall(__global const uchar * obstacles, uint size_x, real x, real y) {
+        return obstacles[(int)floor(x) + size_x * (int)floor(y)];
+    }
+"#;
+
+/// Boundary handling shared by the kernels, mirrors `BoundaryMode` values,
+/// returns -1 when the coordinate falls outside with the respawn mode
+const BOUNDARY_CL: &str = r#"
+    int boundary_coord(int p, int size, uint boundary) {
+        if (p >= 0 && p < size) {
+            return p;
+        }
+        switch (boundary) {
+            case 0: return clamp(p, 0, size - 1);
+            case 1: return ((p % size) + size) % size;
+            case 2: {
+                int m = ((p % (2 * size)) + 2 * size) % (2 * size);
+                return m < size ? m : 2 * size - 1 - m;
+            }
+            default: return -1;
+        }
+    }
+"#;
+
+const SENSE_ROTATE_CL: &str = r#"
+    // Trail of one cell weighted by the species attractions, nothing in
+    // walls and outside with the respawn mode
+    real sensed_cell(__global const real * trails, __global const uchar * obstacles, __global const real * params, int x, int y, uint species_n, uint size_x, uint size_y, uint boundary) {
+        int p_x = boundary_coord(x, size_x, boundary);
+        int p_y = boundary_coord(y, size_y, boundary);
+        if (p_x < 0 || p_y < 0 || obstacles[p_x + p_y * size_x]) {
+            return 0;
+        }
+        real sum = 0;
+        for (uint channel = 0; channel < species_n; channel++) {
+            real attraction = params[8 + channel];
+            if (attraction != 0) {
+                sum += attraction * trails[channel * CELLS + p_x + p_y * size_x];
+            }
+        }
+        return sum;
+    }
+
+    real agent_sense(__global const real * trails, __global const uchar * obstacles, __global const real * params, agent one_agent, real sensor_angle, uint species_n, uint size_x, uint size_y, uint boundary, uint bilinear) {
+        real angle = one_agent.angle + sensor_angle;
+        real x = one_agent.pos_x + params[3] * cos(angle);
+        real y = one_agent.pos_y + params[3] * sin(angle);
+        int sensor_size = (int)params[4];
+        real sum = 0;
+
+        for (int offset_X = -sensor_size; offset_X < sensor_size; offset_X++) {
+        for (int offset_Y = -sensor_size; offset_Y < sensor_size; offset_Y++) {
+            if (bilinear) {
+                // Window centred on the sensor, border cells count by overlap
+                real corner_x = x + offset_X;
+                real corner_y = y + offset_Y;
+                real cell_x = floor(corner_x);
+                real cell_y = floor(corner_y);
+                real share_x = corner_x - cell_x;
+                real share_y = corner_y - cell_y;
+                int c_x = (int)cell_x;
+                int c_y = (int)cell_y;
+                sum += ((real)1 - share_x) * ((real)1 - share_y) * sensed_cell(trails, obstacles, params, c_x, c_y, species_n, size_x, size_y, boundary);
+                sum += share_x * ((real)1 - share_y) * sensed_cell(trails, obstacles, params, c_x + 1, c_y, species_n, size_x, size_y, boundary);
+                sum += ((real)1 - share_x) * share_y * sensed_cell(trails, obstacles, params, c_x, c_y + 1, species_n, size_x, size_y, boundary);
+                sum += share_x * share_y * sensed_cell(trails, obstacles, params, c_x + 1, c_y + 1, species_n, size_x, size_y, boundary);
+            } else {
+                sum += sensed_cell(trails, obstacles, params, (int)round(x) + offset_X, (int)round(y) + offset_Y, species_n, size_x, size_y, boundary);
+            }
+        }}
+        return sum;
+    }
+
+    // Angle of sensor number `sensor` of `sensors` spread over
+    // `±sensor_angle`, from right to left
+    real sensor_offset(uint sensor, uint sensors, real sensor_angle) {
+        return -sensor_angle + 2 * sensor_angle * (real)sensor / (real)(sensors - 1);
+    }
+
+    // Turn of the steering rule given the weights sensed from right to left,
+    // rules numbered like `SteeringRule`
+    real steer(__global const real * params, const real * weights, uint sensors, real random) {
+        real turn = params[1];
+        uint rule = (uint)params[6];
+        real param = params[7];
+        if (rule == 4) {
+            // Strongest sensor, the one nearest forward on ties
+            uint best = 0;
+            for (uint sensor = 1; sensor < sensors; sensor++) {
+                if (weights[sensor] > weights[best] || (weights[sensor] == weights[best] && fabs(sensor_offset(sensor, sensors, params[2])) < fabs(sensor_offset(best, sensors, params[2])))) {
+                    best = sensor;
                 }
             }
+            real offset = sensor_offset(best, sensors, params[2]);
+            return offset < -turn ? -turn : offset > turn ? turn : offset;
         }
-    "#;
 
-    let pro_que = ProQue::builder().src(kernel).dims(1).build()?;
+        real right = weights[0];
+        real forward = weights[1];
+        real left = weights[2];
+        if (rule == 2) {
+            real total = fabs(right) + fabs(forward) + fabs(left);
+            if (total <= 0) {
+                return 0;
+            }
+            real share = param * (left - right) / total;
+            return (share < -1 ? -1 : share > 1 ? 1 : share) * turn;
+        }
+        if (rule == 3) {
+            // Shifted by the strongest so the exponentials stay finite
+            real strongest = fmax(fmax(right, forward), left);
+            real chances[3] = {exp((right - strongest) / param), exp((forward - strongest) / param), exp((left - strongest) / param)};
+            real pick = random * (chances[0] + chances[1] + chances[2]);
+            int chosen = 2;
+            for (int sensor = 0; sensor < 3; sensor++) {
+                if (pick < chances[sensor]) {
+                    chosen = sensor;
+                    break;
+                }
+                pick -= chances[sensor];
+            }
+            return (chosen - 1) * turn;
+        }
 
-    // println!("compile ok");
+        // Random share of the turn, or all of it with the fixed rule
+        real strength = rule == 1 ? 1 : random;
+        if (forward > left && forward > right) {
+            // Keep forward
+            return 0;
+        } else if (forward < left && forward < right) {
+            if (rule == 1) {
+                return random < (real)0.5 ? -turn : turn;
+            }
+            return (random - (real)0.5) * 2 * turn;
+        } else if (right > left) {
+            return -strength * turn;
+        } else if (left > right) {
+            return strength * turn;
+        }
+        return 0;
+    }
 
-    let buffer = pro_que.create_buffer::<Agent>()?;
+    __kernel void sense_rotate(__global agent * agents, __global ulong * rng_states, __global const real * trails, __global const uchar * obstacles, __global const real * species, uint agent_n, uint species_n, uint size_x, uint size_y, uint boundary, uint bilinear) {
+        uint i = get_global_id(0);
+        if (i >= agent_n) {
+            return;
+        }
+        agent one_agent = agents[i];
+        __global const real * params = &species[one_agent.species * SPECIES_STRIDE];
 
-    // println!("buffer create ok");
+        // Sense, from right to left
+        uint rule = (uint)params[6];
+        uint sensors = 3;
+        if (rule == 4) {
+            sensors = (uint)params[7];
+            sensors = sensors < 2 ? 2 : sensors > MAX_FAN_SENSORS ? MAX_FAN_SENSORS : sensors;
+        }
+        real weights[MAX_FAN_SENSORS];
+        for (uint sensor = 0; sensor < sensors; sensor++) {
+            weights[sensor] = agent_sense(trails, obstacles, params, one_agent, sensor_offset(sensor, sensors, params[2]), species_n, size_x, size_y, boundary, bilinear);
+        }
 
-    // println!("{buffer:?}");
+        ulong rng = rng_states[i];
+        real random = rand_real(&rng);
+        rng_states[i] = rng;
 
-    buffer.write(&*agents).enq()?;
-    // println!("buffer write ok");
+        // Rotate
+        agents[i].angle += steer(params, weights, sensors, random);
+    }
+"#;
 
-    let kernel = pro_que
-        .kernel_builder("move")
-        .arg(&buffer)
-        .arg(settings.agent_speed)
-        .arg(settings.agent_n)
-        .arg(settings.size_x)
-        .arg(settings.size_y)
-        .build()?;
+const MOVE_CL: &str = r#"
+    // Mirror a position in [0, size), returns whether it bounced
+    bool reflect_position(real * p, real size) {
+        if (*p < 0) {
+            *p = fmax(fmin(-*p, size - 1), (real)0);
+            return true;
+        } else if (*p >= size) {
+            *p = fmin(fmax(2 * size - *p, (real)0), size - 1);
+            return true;
+        }
+        return false;
+    }
 
-    // println!("kernel launched");
-    unsafe {
-        kernel.enq()?;
+    // Wrap a position in [0, size)
+    real wrap_position(real p, real size) {
+        p = fmod(p, size);
+        if (p < 0) {
+            p += size;
+        }
+        return p < size ? p : 0;
     }
-    // println!("kernel exited");
 
-    buffer.read(agents).enq()?;
-    // println!("buffer readed");
+    // Place an agent as configured by the spawn mode, retrying to land in the
+    // world outside walls, the last try is clamped in the world
+    void spawn_agent(agent * one_agent, ulong * rng, uint spawn, real spawn_radius, __global const uchar * obstacles, uint size_x, uint size_y) {
+        for (int attempt = 0; attempt <= SPAWN_TRIES; attempt++) {
+            real angle = rand_real(rng) * 2 * PI;
+            switch (spawn) {
+                case 1: {
+                    real radius = rand_real(rng) * spawn_radius;
+                    one_agent->pos_x = (real)size_x / 2 + cos(angle) * radius;
+                    one_agent->pos_y = (real)size_y / 2 + sin(angle) * radius;
+                    one_agent->angle = angle + PI;
+                    break;
+                }
+                case 2:
+                    one_agent->pos_x = (real)size_x / 2;
+                    one_agent->pos_y = (real)size_y / 2;
+                    one_agent->angle = angle;
+                    break;
+                default:
+                    one_agent->pos_x = rand_real(rng) * size_x;
+                    one_agent->pos_y = rand_real(rng) * size_y;
+                    one_agent->angle = angle;
+            }
+            if (inside(one_agent->pos_x, one_agent->pos_y, size_x, size_y) &&
+                !is_wall(obstacles, size_x, one_agent->pos_x, one_agent->pos_y)) {
+                return;
+            }
+        }
+        // Spawn shape mostly outside the world, or a world full of walls
+        one_agent->pos_x = fmin(fmax(one_agent->pos_x, (real)0), (real)size_x - 1);
+        one_agent->pos_y = fmin(fmax(one_agent->pos_y, (real)0), (real)size_y - 1);
+    }
 
-    Ok(())
-}
+    // Agents in each cell before exclusive moves
+    __kernel void occupy(__global const agent * agents, __global uint * occupancy, uint agent_n, uint size_x, uint size_y) {
+        uint i = get_global_id(0);
+        if (i >= agent_n) {
+            return;
+        }
+        agent one_agent = agents[i];
+        if (inside(one_agent.pos_x, one_agent.pos_y, size_x, size_y)) {
+            atomic_inc(&occupancy[(int)floor(one_agent.pos_x) + size_x * (int)floor(one_agent.pos_y)]);
+        }
+    }
+
+    __kernel void move(__global agent * agents, __global ulong * rng_states, __global const uchar * obstacles, __global const real * species, __global uint * occupancy, __global uchar * blocked, uint agent_n, uint size_x, uint size_y, uint boundary, uint spawn, real spawn_radius, uint exclusive) {
+        uint i = get_global_id(0);
+        if (i >= agent_n) {
+            return;
+        }
+        agent one_agent = agents[i];
+        ulong rng = rng_states[i];
+        real old_x = one_agent.pos_x;
+        real old_y = one_agent.pos_y;
+        real agent_speed = species[one_agent.species * SPECIES_STRIDE];
+
+        one_agent.pos_x += cos(one_agent.angle) * agent_speed;
+        one_agent.pos_y += sin(one_agent.angle) * agent_speed;
 
-pub fn gpu_diffuse(trail_map: &mut TrailMap, settings: &Settings) -> ocl::Result<()> {
-    let kernel = r#"
-        __kernel void diffuse(__global double* trailmap, double trail_diffuse, uint max_size_x, uint max_size_y) {
-            int x = get_global_id(0) % max_size_x;
-            int y = get_global_id(0) / max_size_x;
-            double sum = 0;
+        // Check Collision
+        bool respawned = false;
+        if (!inside(one_agent.pos_x, one_agent.pos_y, size_x, size_y)) {
+            switch (boundary) {
+                case 0:
+                    one_agent.pos_x = fmin(fmax(one_agent.pos_x, (real)0), (real)size_x - 1);
+                    one_agent.pos_y = fmin(fmax(one_agent.pos_y, (real)0), (real)size_y - 1);
+                    one_agent.angle = rand_real(&rng) * 2 * PI;
+                    break;
+                case 1:
+                    one_agent.pos_x = wrap_position(one_agent.pos_x, size_x);
+                    one_agent.pos_y = wrap_position(one_agent.pos_y, size_y);
+                    break;
+                case 2:
+                    if (reflect_position(&one_agent.pos_x, size_x)) {
+                        one_agent.angle = PI - one_agent.angle;
+                    }
+                    if (reflect_position(&one_agent.pos_y, size_y)) {
+                        one_agent.angle = -one_agent.angle;
+                    }
+                    break;
+                default:
+                    spawn_agent(&one_agent, &rng, spawn, spawn_radius, obstacles, size_x, size_y);
+                    respawned = true;
+            }
+        }
+
+        // Check Obstacle, agents caught in a new wall are let out
+        bool was_inside = inside(old_x, old_y, size_x, size_y);
+        if (!respawned &&
+            is_wall(obstacles, size_x, one_agent.pos_x, one_agent.pos_y) &&
+            !(was_inside && is_wall(obstacles, size_x, old_x, old_y))) {
+            switch (boundary) {
+                case 0:
+                case 1:
+                    one_agent.angle = rand_real(&rng) * 2 * PI;
+                    break;
+                case 2: {
+                    bool blocked_x = was_inside && is_wall(obstacles, size_x, one_agent.pos_x, old_y);
+                    bool blocked_y = was_inside && is_wall(obstacles, size_x, old_x, one_agent.pos_y);
+                    if (blocked_x) {
+                        one_agent.angle = PI - one_agent.angle;
+                    }
+                    if (blocked_y) {
+                        one_agent.angle = -one_agent.angle;
+                    }
+                    if (!blocked_x && !blocked_y) {
+                        one_agent.angle += PI;
+                    }
+                    break;
+                }
+                default:
+                    spawn_agent(&one_agent, &rng, spawn, spawn_radius, obstacles, size_x, size_y);
+                    respawned = true;
+            }
+            if (!respawned && was_inside) {
+                one_agent.pos_x = old_x;
+                one_agent.pos_y = old_y;
+            }
+        }
 
-            for (int offset_X = -1; offset_X <= 1; offset_X++) {
-            for (int offset_Y = -1; offset_Y <= 1; offset_Y++) {
+        // Exclusive occupancy, the destination cell is claimed atomically,
+        // agents finding it taken stay and turn to a random heading. Unlike
+        // the CPU order, an agent can be blocked by one leaving the cell
+        if (exclusive) {
+            bool stuck = false;
+            if (was_inside && inside(one_agent.pos_x, one_agent.pos_y, size_x, size_y)) {
+                int from = (int)floor(old_x) + size_x * (int)floor(old_y);
+                int to = (int)floor(one_agent.pos_x) + size_x * (int)floor(one_agent.pos_y);
+                if (to != from) {
+                    if (atomic_cmpxchg(&occupancy[to], 0, 1) == 0) {
+                        atomic_dec(&occupancy[from]);
+                    } else {
+                        one_agent.pos_x = old_x;
+                        one_agent.pos_y = old_y;
+                        one_agent.angle = rand_real(&rng) * 2 * PI;
+                        stuck = true;
+                    }
+                }
+            }
+            blocked[i] = stuck;
+        }
 
-                int p_x = x + offset_X;
-                int p_y = y + offset_Y;
+        agents[i] = one_agent;
+        rng_states[i] = rng;
+    }
+"#;
 
-            if ( p_x >= 0 && p_y >= 0 && p_x < max_size_x && p_y < max_size_y ) {
-                sum += trailmap[p_x + p_y * max_size_x];
+const DEPOSIT_CL: &str = r#"
+    void cover_cell(__global uint * channel, int x, int y, uint share, uint size_x, uint size_y, uint boundary) {
+        int p_x = boundary_coord(x, size_x, boundary);
+        int p_y = boundary_coord(y, size_y, boundary);
+        if (share > 0 && p_x >= 0 && p_y >= 0) {
+            atomic_add(&channel[p_x + p_y * size_x], share);
+        }
+    }
 
+    // Agents only add their share of each cell they cover, in
+    // `SUBCELL_STEPS²` units, integer atomics work on every device where
+    // floating point ones do not
+    __kernel void cover(__global const agent * agents, __global uint * coverage, __global const uchar * blocked, uint agent_n, uint size_x, uint size_y, uint boundary, uint bilinear, uint exclusive) {
+        uint i = get_global_id(0);
+        // Agents blocked by an exclusive move leave no trail
+        if (i >= agent_n || (exclusive && blocked[i])) {
+            return;
+        }
+        agent one_agent = agents[i];
+        if (!inside(one_agent.pos_x, one_agent.pos_y, size_x, size_y)) {
+            return;
+        }
+        __global uint * channel = &coverage[one_agent.species * CELLS];
+        if (!bilinear) {
+            int cell = (int)floor(one_agent.pos_x) + size_x * (int)floor(one_agent.pos_y);
+            atomic_add(&channel[cell], SUBCELL_STEPS * SUBCELL_STEPS);
+            return;
+        }
+        real x = one_agent.pos_x - (real)0.5;
+        real y = one_agent.pos_y - (real)0.5;
+        real cell_x = floor(x);
+        real cell_y = floor(y);
+        uint share_x = (uint)round((x - cell_x) * SUBCELL_STEPS);
+        uint share_y = (uint)round((y - cell_y) * SUBCELL_STEPS);
+        int c_x = (int)cell_x;
+        int c_y = (int)cell_y;
+        cover_cell(channel, c_x, c_y, (SUBCELL_STEPS - share_x) * (SUBCELL_STEPS - share_y), size_x, size_y, boundary);
+        cover_cell(channel, c_x + 1, c_y, share_x * (SUBCELL_STEPS - share_y), size_x, size_y, boundary);
+        cover_cell(channel, c_x, c_y + 1, (SUBCELL_STEPS - share_x) * share_y, size_x, size_y, boundary);
+        cover_cell(channel, c_x + 1, c_y + 1, share_x * share_y, size_x, size_y, boundary);
+    }
 
+    // One cell per work item deposits the weight of all the agents covering
+    // it at once, mirrors `DepositMode` values, and clears the coverage for
+    // the next step
+    __kernel void deposit(__global real * trails, __global uint * coverage, __global const real * species, uint species_n, uint deposit_mode, real deposit_cap) {
+        for (uint channel = 0; channel < species_n; channel++) {
+            uint cell = channel * CELLS + get_global_id(0);
+            uint share = coverage[cell];
+            if (share == 0) {
+                continue;
+            }
+            coverage[cell] = 0;
+            real covered = (real)share / (SUBCELL_STEPS * SUBCELL_STEPS);
+            real weight = species[channel * SPECIES_STRIDE + 5];
+            real value = trails[cell];
+            switch (deposit_mode) {
+                case 0: trails[cell] = covered >= 1 ? weight : value + (weight - value) * covered; break;
+                case 1: trails[cell] = value + weight * covered; break;
+                case 2: trails[cell] = fmin(value + weight * covered, deposit_cap); break;
+                default: trails[cell] = fmax(value, weight * fmin(covered, (real)1)); break;
             }
+        }
+    }
+
+    __kernel void inject_nutrients(__global real * trails, __global const real * nutrients, __global const uchar * obstacles, uint species_n) {
+        real nutrient = nutrients[get_global_id(0)];
+        if (nutrient > 0 && !obstacles[get_global_id(0)]) {
+            for (uint channel = 0; channel < species_n; channel++) {
+                trails[channel * CELLS + get_global_id(0)] += nutrient;
+            }
+        }
+    }
+"#;
 
-            }}
-            trailmap[get_global_id(0)] *= (double)1 - trail_diffuse;
-            trailmap[get_global_id(0)] += sum / (double)9 * trail_diffuse;
+const DIFFUSE_DECAY_CL: &str = r#"
+    __kernel void diffuse_decay(__global const real* source, __global real* trails, __global const uchar* obstacles, __global const real* stencil, int radius, real stencil_total, uint channel, uint size_x, uint size_y, uint boundary, real trail_diffuse, real decay_factor, real decay_amount) {
+
+        int x = get_global_id(0) % size_x;
+        int y = get_global_id(0) / size_x;
+        __global real * trailmap = &trails[channel * CELLS];
+        if (obstacles[get_global_id(0)]) {
+            trailmap[get_global_id(0)] = 0;
+            return;
         }
-    "#;
+        real sum = 0;
+        real weight = stencil_total;
+
+        for (int offset_X = -radius; offset_X <= radius; offset_X++) {
+        for (int offset_Y = -radius; offset_Y <= radius; offset_Y++) {
 
-    let pro_que = ProQue::builder()
-        .src(kernel)
-        .dims(MAX_SIZE_X * MAX_SIZE_Y)
-        .build()?;
+            int p_x = boundary_coord(x + offset_X, size_x, boundary);
+            int p_y = boundary_coord(y + offset_Y, size_y, boundary);
+            real stencil_weight = stencil[(offset_Y + radius) * (2 * radius + 1) + offset_X + radius];
 
-    let buffer = pro_que.create_buffer::<f64>()?;
-    buffer.write(&*trail_map).enq()?;
+            if ( p_x >= 0 && p_y >= 0 ) {
+                if (obstacles[p_x + p_y * size_x]) {
+                    weight -= stencil_weight;
+                } else {
+                    sum += stencil_weight * source[p_x + p_y * size_x];
+                }
+            }
 
-    let kernel = pro_que
-        .kernel_builder("diffuse")
-        .arg(&buffer)
-        .arg(settings.trail_diffuse)
-        .arg(MAX_SIZE_X)
-        .arg(MAX_SIZE_Y)
-        .build()?;
+        }}
+        if (weight != 0) {
+            sum /= weight;
+        }
+        real value = source[get_global_id(0)] * ((real)1 - trail_diffuse)
+            + sum * trail_diffuse;
 
-    unsafe {
-        kernel.enq()?;
+        trailmap[get_global_id(0)] = fmax(value * decay_factor - decay_amount, (real)0);
     }
+"#;
 
-    buffer.read(trail_map).enq()?;
-    Ok(())
+/// Phases of a step, each one a kernel or a kernel per trail channel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    SenseRotate,
+    Move,
+    Deposit,
+    Nutrients,
+    DiffuseDecay,
 }
 
-pub fn gpu_decay(trail_map: &mut TrailMap, settings: &Settings) -> ocl::Result<()> {
-    let kernel = r#"
-        __kernel void decay(__global double* trailmap, double trail_decay) {
-            if (trailmap[get_global_id(0)] < trail_decay) {
-                trailmap[get_global_id(0)] = 0;
-            } else {
-                trailmap[get_global_id(0)] -= trail_decay;
+impl Phase {
+    /// All phases in step order
+    pub const ALL: [Phase; 5] = [
+        Phase::SenseRotate,
+        Phase::Move,
+        Phase::Deposit,
+        Phase::Nutrients,
+        Phase::DiffuseDecay,
+    ];
+}
+
+#[derive(Debug)]
+pub enum GpuError {
+    /// No OpenCL platform exposes a device
+    NoDevice,
+    /// The kernels failed to compile on the device, with the compiler output
+    Build {
+        device: String,
+        log: String,
+    },
+    Ocl(ocl::Error),
+}
+
+impl fmt::Display for GpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GpuError::NoDevice => write!(f, "no OpenCL device found"),
+            GpuError::Build { device, log } => {
+                write!(f, "cannot build OpenCL kernels for {device}:\n{log}")
             }
+            GpuError::Ocl(e) => write!(f, "OpenCL error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for GpuError {}
+
+impl From<ocl::Error> for GpuError {
+    fn from(e: ocl::Error) -> Self {
+        GpuError::Ocl(e)
+    }
+}
+
+impl From<ocl::OclCoreError> for GpuError {
+    fn from(e: ocl::OclCoreError) -> Self {
+        GpuError::Ocl(e.into())
+    }
+}
+
+/// An OpenCL device and the platform exposing it
+#[derive(Clone, Debug)]
+pub struct GpuDevice {
+    pub platform: Platform,
+    pub device: Device,
+    /// Platform and device names for display
+    pub name: String,
+}
+
+impl GpuDevice {
+    /// Whether the device runs `double` kernels
+    pub fn supports_double(&self) -> bool {
+        self.device
+            .info(DeviceInfo::Extensions)
+            .is_ok_and(|extensions| extensions.to_string().contains("cl_khr_fp64"))
+    }
+}
+
+/// All devices of all OpenCL platforms, empty when no ICD is installed
+pub fn devices() -> Vec<GpuDevice> {
+    let Ok(platforms) = core::get_platform_ids() else {
+        return Vec::new();
+    };
+    Platform::list_from_core(platforms)
+        .into_iter()
+        .flat_map(|platform| {
+            Device::list_all(platform)
+                .unwrap_or_default()
+                .into_iter()
+                .map(move |device| GpuDevice {
+                    name: format!(
+                        "{} - {}",
+                        platform.name().unwrap_or_default(),
+                        device.name().unwrap_or_default()
+                    ),
+                    platform,
+                    device,
+                })
+        })
+        .collect()
+}
+
+/// Compile the kernels for one device, keeping the build log on failure
+fn build_program(
+    context: &Context,
+    device: &GpuDevice,
+    options: &str,
+    src: String,
+) -> Result<Program, GpuError> {
+    let program =
+        core::create_program_with_source(context, &[CString::new(src).map_err(ocl::Error::from)?])?;
+    let options = CString::new(options).map_err(ocl::Error::from)?;
+    if core::build_program(&program, Some(&[device.device]), &options, None, None).is_err() {
+        let log =
+            match core::get_program_build_info(&program, device.device, ProgramBuildInfo::BuildLog)
+            {
+                Ok(ProgramBuildInfoResult::BuildLog(log)) => log,
+                Ok(other) => other.to_string(),
+                Err(e) => e.to_string(),
+            };
+        return Err(GpuError::Build {
+            device: device.name.clone(),
+            log,
+        });
+    }
+    Ok(Program::from(program))
+}
+
+/// Float type of the device buffers and kernels
+trait DeviceReal: Real + OclPrm {
+    /// Source defining `real`, `PI` and the random bits it holds
+    const HEADER: &'static str;
+    /// Compiler options
+    const OPTIONS: &'static str;
+}
+
+impl DeviceReal for f32 {
+    const HEADER: &'static str = "typedef float real;\n#define PI M_PI_F\n#define RAND_BITS 24\n";
+    const OPTIONS: &'static str = "-cl-single-precision-constant";
+}
+
+impl DeviceReal for f64 {
+    const HEADER: &'static str = "#pragma OPENCL EXTENSION cl_khr_fp64 : enable\ntypedef double real;\n#define PI M_PI\n#define RAND_BITS 53\n";
+    const OPTIONS: &'static str = "";
+}
+
+/// `Agent` with the device float type, same layout as the kernels `agent`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C)]
+struct DeviceAgent<R> {
+    pos_x: R,
+    pos_y: R,
+    angle: R,
+    species: u32,
+}
+
+unsafe impl<R: DeviceReal> OclPrm for DeviceAgent<R> {}
+
+impl<R: DeviceReal, H: Real> From<&Agent<H>> for DeviceAgent<R> {
+    fn from(agent: &Agent<H>) -> Self {
+        DeviceAgent {
+            pos_x: R::from_f64(agent.pos_x.to_f64()),
+            pos_y: R::from_f64(agent.pos_y.to_f64()),
+            angle: R::from_f64(agent.angle.to_f64()),
+            species: agent.species,
         }
-    "#;
+    }
+}
 
-    let pro_que = ProQue::builder()
-        .src(kernel)
-        .dims(MAX_SIZE_X * MAX_SIZE_Y)
-        .build()?;
+impl<R: DeviceReal, H: Real> From<DeviceAgent<R>> for Agent<H> {
+    fn from(agent: DeviceAgent<R>) -> Self {
+        Agent {
+            pos_x: H::from_f64(agent.pos_x.to_f64()),
+            pos_y: H::from_f64(agent.pos_y.to_f64()),
+            angle: H::from_f64(agent.angle.to_f64()),
+            species: agent.species,
+            energy: 0.0,
+            blocked: false,
+        }
+    }
+}
 
-    let buffer = pro_que.create_buffer::<f64>()?;
-    buffer.write(&*trail_map).enq()?;
+/// Host values, in any float type, converted to the device one
+fn to_device<R: DeviceReal, H: Real>(values: &[H]) -> Vec<R> {
+    values
+        .iter()
+        .map(|value| R::from_f64(value.to_f64()))
+        .collect()
+}
 
-    let kernel = pro_que
-        .kernel_builder("decay")
-        .arg(&buffer)
-        .arg(settings.trail_decay)
-        .build()?;
+/// Species parameters in the layout read by the kernels, angles in radians
+fn species_params<R: DeviceReal>(settings: &Settings) -> Vec<R> {
+    settings
+        .species
+        .iter()
+        .flat_map(|species| {
+            [
+                species.agent_speed,
+                species.agent_turn.to_radians(),
+                species.sensor_angle.to_radians(),
+                species.sensor_distance,
+                species.sensor_size as f64,
+                species.trail_weight,
+            ]
+            .into_iter()
+            .chain(steering_params(&species.steering))
+            .chain(species.attraction)
+        })
+        .map(R::from_f64)
+        .collect()
+}
 
-    unsafe {
-        kernel.enq()?;
+/// Number of a steering rule in the kernels and its parameter
+fn steering_params(rule: &SteeringRule) -> [f64; 2] {
+    match *rule {
+        SteeringRule::Random => [0.0, 0.0],
+        SteeringRule::Fixed => [1.0, 0.0],
+        SteeringRule::Gradient { gain } => [2.0, gain],
+        SteeringRule::Softmax { temperature } => [3.0, temperature],
+        SteeringRule::Fan { sensors } => [4.0, sensors as f64],
     }
+}
+
+/// SplitMix64, spreads one seed over the agents random generators
+fn splitmix64(state: u64) -> u64 {
+    let mut z = state.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
 
-    buffer.read(trail_map).enq()?;
-    Ok(())
+/// Kernels and device buffers for one float type
+struct Pipeline<R: DeviceReal> {
+    /// Cells of one trail channel
+    cells: usize,
+    queue: Queue,
+    agents: Buffer<DeviceAgent<R>>,
+    rng_states: Buffer<u64>,
+    species: Buffer<R>,
+    /// Weights of the diffusion kernel
+    stencil: Buffer<R>,
+    trails: Buffer<R>,
+    /// Share of each cell covered by agents of each channel, between the two
+    /// deposit kernels
+    coverage: Buffer<u32>,
+    /// Agents in each cell during exclusive moves
+    occupancy: Buffer<u32>,
+    /// Whether each agent found its destination taken in the last exclusive
+    /// move
+    blocked: Buffer<u8>,
+    scratch: Buffer<R>,
+    nutrients: Buffer<R>,
+    obstacles: Buffer<u8>,
+    sense_rotate_kernel: Kernel,
+    occupy_kernel: Kernel,
+    move_kernel: Kernel,
+    cover_kernel: Kernel,
+    deposit_kernel: Kernel,
+    inject_kernel: Kernel,
+    diffuse_decay_kernel: Kernel,
 }
 
-pub fn gpu_all(trail_map: &mut TrailMap, settings: &Settings) -> ocl::Result<()> {
-    let kernel = r#"
-            __kernel void all(__global double* trailmap, uint max_size_x, uint max_size_y, double trail_diffuse, double trail_decay) {
+impl<R: DeviceReal> Pipeline<R> {
+    /// Compile the kernels and allocate buffers for a world of `cells` cells
+    fn new(device: &GpuDevice, cells: usize) -> Result<Self, GpuError> {
+        let context = Context::builder()
+            .platform(device.platform)
+            .devices(device.device)
+            .build()?;
+        let queue = Queue::new(&context, device.device, None)?;
+        let defines = format!(
+            "#define CELLS {cells}\n#define SPECIES_STRIDE {SPECIES_STRIDE}\n#define SPAWN_TRIES {SPAWN_TRIES}\n#define SUBCELL_STEPS {SUBCELL_STEPS}\n#define MAX_FAN_SENSORS {MAX_FAN_SENSORS}\n"
+        );
+        let program = build_program(
+            &context,
+            device,
+            R::OPTIONS,
+            format!(
+                "{}{defines}{COMMON_CL}{BOUNDARY_CL}{SENSE_ROTATE_CL}{MOVE_CL}{DEPOSIT_CL}{DIFFUSE_DECAY_CL}",
+                R::HEADER
+            ),
+        )?;
+        let pro_que = ProQue::new(context, queue.clone(), program, Some(cells));
+
+        let agents = pro_que
+            .buffer_builder::<DeviceAgent<R>>()
+            .len(MAX_AGENT_N as usize)
+            .build()?;
+        let rng_states = pro_que
+            .buffer_builder::<u64>()
+            .len(MAX_AGENT_N as usize)
+            .build()?;
+        let species = pro_que
+            .buffer_builder::<R>()
+            .len(MAX_SPECIES * SPECIES_STRIDE)
+            .build()?;
+        let stencil = pro_que
+            .buffer_builder::<R>()
+            .len((2 * MAX_KERNEL_RADIUS + 1).pow(2))
+            .build()?;
+        let trails = pro_que
+            .buffer_builder::<R>()
+            .len(MAX_SPECIES * cells)
+            .fill_val(R::default())
+            .build()?;
+        let coverage = pro_que
+            .buffer_builder::<u32>()
+            .len(MAX_SPECIES * cells)
+            .fill_val(0)
+            .build()?;
+        let occupancy = pro_que.create_buffer::<u32>()?;
+        let blocked = pro_que
+            .buffer_builder::<u8>()
+            .len(MAX_AGENT_N as usize)
+            .fill_val(0)
+            .build()?;
+        let scratch = pro_que.create_buffer::<R>()?;
+        let nutrients = pro_que.create_buffer::<R>()?;
+        let obstacles = pro_que.create_buffer::<u8>()?;
+
+        let sense_rotate_kernel = pro_que
+            .kernel_builder("sense_rotate")
+            .arg(&agents)
+            .arg(&rng_states)
+            .arg(&trails)
+            .arg(&obstacles)
+            .arg(&species)
+            .arg_named("agent_n", 0_u32)
+            .arg_named("species_n", 0_u32)
+            .arg_named("size_x", 0_u32)
+            .arg_named("size_y", 0_u32)
+            .arg_named("boundary", 0_u32)
+            .arg_named("bilinear", 0_u32)
+            .build()?;
+
+        let occupy_kernel = pro_que
+            .kernel_builder("occupy")
+            .arg(&agents)
+            .arg(&occupancy)
+            .arg_named("agent_n", 0_u32)
+            .arg_named("size_x", 0_u32)
+            .arg_named("size_y", 0_u32)
+            .build()?;
 
-            int x = get_global_id(0) % max_size_x;
-            int y = get_global_id(0) / max_size_x;
-            double sum = 0;
+        let move_kernel = pro_que
+            .kernel_builder("move")
+            .arg(&agents)
+            .arg(&rng_states)
+            .arg(&obstacles)
+            .arg(&species)
+            .arg(&occupancy)
+            .arg(&blocked)
+            .arg_named("agent_n", 0_u32)
+            .arg_named("size_x", 0_u32)
+            .arg_named("size_y", 0_u32)
+            .arg_named("boundary", 0_u32)
+            .arg_named("spawn", 0_u32)
+            .arg_named("spawn_radius", R::default())
+            .arg_named("exclusive", 0_u32)
+            .build()?;
 
-            for (int offset_X = -1; offset_X <= 1; offset_X++) {
-            for (int offset_Y = -1; offset_Y <= 1; offset_Y++) {
+        let cover_kernel = pro_que
+            .kernel_builder("cover")
+            .arg(&agents)
+            .arg(&coverage)
+            .arg(&blocked)
+            .arg_named("agent_n", 0_u32)
+            .arg_named("size_x", 0_u32)
+            .arg_named("size_y", 0_u32)
+            .arg_named("boundary", 0_u32)
+            .arg_named("bilinear", 0_u32)
+            .arg_named("exclusive", 0_u32)
+            .build()?;
 
-                int p_x = x + offset_X;
-                int p_y = y + offset_Y;
+        let deposit_kernel = pro_que
+            .kernel_builder("deposit")
+            .arg(&trails)
+            .arg(&coverage)
+            .arg(&species)
+            .arg_named("species_n", 0_u32)
+            .arg_named("deposit_mode", 0_u32)
+            .arg_named("deposit_cap", R::default())
+            .build()?;
 
-            if ( p_x >= 0 && p_y >= 0 && p_x < max_size_x && p_y < max_size_y ) {
-                sum += trailmap[p_x + p_y * max_size_x];
+        let inject_kernel = pro_que
+            .kernel_builder("inject_nutrients")
+            .arg(&trails)
+            .arg(&nutrients)
+            .arg(&obstacles)
+            .arg_named("species_n", 0_u32)
+            .build()?;
 
+        let diffuse_decay_kernel = pro_que
+            .kernel_builder("diffuse_decay")
+            .arg(&scratch)
+            .arg(&trails)
+            .arg(&obstacles)
+            .arg(&stencil)
+            .arg_named("radius", 0_i32)
+            .arg_named("stencil_total", R::default())
+            .arg_named("channel", 0_u32)
+            .arg_named("size_x", 0_u32)
+            .arg_named("size_y", 0_u32)
+            .arg_named("boundary", 0_u32)
+            .arg_named("trail_diffuse", R::default())
+            .arg_named("decay_factor", R::default())
+            .arg_named("decay_amount", R::default())
+            .build()?;
 
+        Ok(Pipeline {
+            cells,
+            queue,
+            agents,
+            rng_states,
+            species,
+            stencil,
+            trails,
+            coverage,
+            occupancy,
+            blocked,
+            scratch,
+            nutrients,
+            obstacles,
+            sense_rotate_kernel,
+            occupy_kernel,
+            move_kernel,
+            cover_kernel,
+            deposit_kernel,
+            inject_kernel,
+            diffuse_decay_kernel,
+        })
+    }
+
+    /// Replace the whole device state with the host one, agents random
+    /// generators are derived from `seed`
+    fn upload<H: Real>(
+        &self,
+        agents: &Agents<H>,
+        trail_maps: &[TrailMap<H>],
+        nutrient_map: &NutrientMap,
+        obstacle_map: &ObstacleMap,
+        seed: u64,
+    ) -> ocl::Result<()> {
+        let agents: Vec<DeviceAgent<R>> = agents.iter().map(DeviceAgent::from).collect();
+        self.agents.write(&agents).enq()?;
+
+        let rng_states: Vec<u64> = (0..self.rng_states.len() as u64)
+            .map(|i| splitmix64(seed ^ splitmix64(i)) | 1)
+            .collect();
+        self.rng_states.write(&rng_states).enq()?;
+
+        for (channel, trail_map) in trail_maps.iter().enumerate() {
+            self.trails
+                .write(&to_device(trail_map))
+                .offset(channel * self.cells)
+                .enq()?;
+        }
+        self.coverage.cmd().fill(0, None).enq()?;
+        self.blocked.cmd().fill(0, None).enq()?;
+        self.nutrients.write(&to_device(nutrient_map)).enq()?;
+
+        let obstacles: Vec<u8> = obstacle_map.iter().map(|&wall| wall as u8).collect();
+        self.obstacles.write(&obstacles).enq()
+    }
+
+    /// Replace the living agents with the host ones, their random generators
+    /// are kept
+    fn upload_agents<H: Real>(&self, agents: &Agents<H>, settings: &Settings) -> ocl::Result<()> {
+        if settings.agent_n == 0 {
+            return Ok(());
+        }
+        let agents: Vec<DeviceAgent<R>> = agents[..settings.agent_n as usize]
+            .iter()
+            .map(DeviceAgent::from)
+            .collect();
+        self.agents.write(&agents).enq()
+    }
+
+    fn read_agents<H: Real>(&self, agents: &mut Agents<H>, settings: &Settings) -> ocl::Result<()> {
+        if settings.agent_n == 0 {
+            return Ok(());
+        }
+        let mut device_agents = vec![DeviceAgent::<R>::default(); settings.agent_n as usize];
+        self.agents.read(&mut device_agents).enq()?;
+        for (agent, device_agent) in agents.iter_mut().zip(device_agents) {
+            // Energy only lives on the host
+            *agent = Agent {
+                energy: agent.energy,
+                ..device_agent.into()
+            };
+        }
+        Ok(())
+    }
+
+    fn read_trails<H: Real>(&self, trail_maps: &mut [TrailMap<H>]) -> ocl::Result<()> {
+        let mut values = vec![R::default(); self.cells];
+        for (channel, trail_map) in trail_maps.iter_mut().enumerate() {
+            self.trails
+                .read(&mut values)
+                .offset(channel * self.cells)
+                .enq()?;
+            for (cell, value) in trail_map.iter_mut().zip(&values) {
+                *cell = H::from_f64(value.to_f64());
             }
+        }
+        Ok(())
+    }
 
-            }}
-            trailmap[get_global_id(0)] *= (double)1 - trail_diffuse;
-            trailmap[get_global_id(0)] += sum / (double)9 * trail_diffuse;
+    /// Species parameters and diffusion kernel of `settings`
+    fn write_params(&self, settings: &Settings) -> ocl::Result<()> {
+        self.species.write(&species_params::<R>(settings)).enq()?;
+        let stencil = settings.diffusion.stencil();
+        self.stencil.write(&to_device(&stencil.weights)).enq()?;
+        let kernel = &self.diffuse_decay_kernel;
+        kernel.set_arg("radius", stencil.radius as i32)?;
+        kernel.set_arg("stencil_total", R::from_f64(stencil.weights.iter().sum()))
+    }
 
+    /// Enqueue the kernels of one phase
+    fn enqueue(&self, phase: Phase, settings: &Settings) -> ocl::Result<()> {
+        // Kernels skip ids past `agent_n`, an empty work size is an error
+        let agent_n = (settings.agent_n as usize).max(1);
+        let species_n = settings.species_n as u32;
+        let boundary = settings.boundary as u32;
+        let exclusive = settings.movement == MovementMode::Exclusive;
 
-            if (trailmap[get_global_id(0)] < trail_decay) {
-                trailmap[get_global_id(0)] = 0;
-            } else {
-                trailmap[get_global_id(0)] -= trail_decay;
+        match phase {
+            Phase::SenseRotate => {
+                let kernel = &self.sense_rotate_kernel;
+                kernel.set_arg("agent_n", settings.agent_n)?;
+                kernel.set_arg("species_n", species_n)?;
+                kernel.set_arg("size_x", settings.size_x)?;
+                kernel.set_arg("size_y", settings.size_y)?;
+                kernel.set_arg("boundary", boundary)?;
+                kernel.set_arg("bilinear", settings.bilinear as u32)?;
+                unsafe { kernel.cmd().global_work_size(agent_n).enq() }
+            }
+            Phase::Move => {
+                if exclusive {
+                    self.occupancy.cmd().fill(0, None).enq()?;
+                    let kernel = &self.occupy_kernel;
+                    kernel.set_arg("agent_n", settings.agent_n)?;
+                    kernel.set_arg("size_x", settings.size_x)?;
+                    kernel.set_arg("size_y", settings.size_y)?;
+                    unsafe { kernel.cmd().global_work_size(agent_n).enq()? };
+                }
+                let kernel = &self.move_kernel;
+                kernel.set_arg("agent_n", settings.agent_n)?;
+                kernel.set_arg("size_x", settings.size_x)?;
+                kernel.set_arg("size_y", settings.size_y)?;
+                kernel.set_arg("boundary", boundary)?;
+                kernel.set_arg("spawn", settings.spawn as u32)?;
+                kernel.set_arg("spawn_radius", R::from_f64(settings.spawn_radius))?;
+                kernel.set_arg("exclusive", exclusive as u32)?;
+                unsafe { kernel.cmd().global_work_size(agent_n).enq() }
+            }
+            Phase::Deposit => {
+                let kernel = &self.cover_kernel;
+                kernel.set_arg("agent_n", settings.agent_n)?;
+                kernel.set_arg("size_x", settings.size_x)?;
+                kernel.set_arg("size_y", settings.size_y)?;
+                kernel.set_arg("boundary", boundary)?;
+                kernel.set_arg("bilinear", settings.bilinear as u32)?;
+                kernel.set_arg("exclusive", exclusive as u32)?;
+                unsafe { kernel.cmd().global_work_size(agent_n).enq()? };
+                let kernel = &self.deposit_kernel;
+                kernel.set_arg("species_n", species_n)?;
+                kernel.set_arg("deposit_mode", settings.deposit as u32)?;
+                kernel.set_arg("deposit_cap", R::from_f64(settings.deposit_cap))?;
+                unsafe { kernel.cmd().global_work_size(self.cells).enq() }
+            }
+            Phase::Nutrients => {
+                let kernel = &self.inject_kernel;
+                kernel.set_arg("species_n", species_n)?;
+                unsafe { kernel.cmd().global_work_size(self.cells).enq() }
+            }
+            // From a copy so neighbours see the previous step values
+            Phase::DiffuseDecay => {
+                let kernel = &self.diffuse_decay_kernel;
+                kernel.set_arg("size_x", settings.size_x)?;
+                kernel.set_arg("size_y", settings.size_y)?;
+                kernel.set_arg("boundary", boundary)?;
+                kernel.set_arg("trail_diffuse", R::from_f64(settings.trail_diffuse))?;
+                let (decay_factor, decay_amount) = settings.decay();
+                kernel.set_arg("decay_factor", R::from_f64(decay_factor))?;
+                kernel.set_arg("decay_amount", R::from_f64(decay_amount))?;
+                for channel in 0..settings.species_n as usize {
+                    self.trails
+                        .cmd()
+                        .offset(channel * self.cells)
+                        .copy(&self.scratch, None, Some(self.cells))
+                        .enq()?;
+                    kernel.set_arg("channel", channel as u32)?;
+                    unsafe {
+                        kernel.cmd().global_work_size(self.cells).enq()?;
+                    }
+                }
+                Ok(())
             }
         }
-        "#;
+    }
+
+    /// Run one phase alone and wait for it
+    fn run_phase(&self, phase: Phase, settings: &Settings) -> ocl::Result<()> {
+        self.write_params(settings)?;
+        self.enqueue(phase, settings)?;
+        self.queue.finish()
+    }
 
-    let pro_que = ProQue::builder()
-        .src(kernel)
-        .dims(MAX_SIZE_X * MAX_SIZE_Y)
-        .build()?;
+    /// Run all steps once on the device, waiting for each phase to time it
+    /// when `timed` and only for the whole step otherwise, phase times being
+    /// left at zero
+    fn step(&self, settings: &Settings, timed: bool) -> ocl::Result<StepTimings> {
+        self.write_params(settings)?;
 
-    let buffer = pro_que.create_buffer::<f64>()?;
-    buffer.write(&*trail_map).enq()?;
+        let mut timings = StepTimings::default();
+        if !timed {
+            for phase in Phase::ALL {
+                self.enqueue(phase, settings)?;
+            }
+            self.queue.finish()?;
+            return Ok(timings);
+        }
+        for phase in Phase::ALL {
+            let start = Instant::now();
+            self.enqueue(phase, settings)?;
+            self.queue.finish()?;
+            let elapsed = start.elapsed();
+            match phase {
+                Phase::SenseRotate => timings.sense_rotate = elapsed,
+                Phase::Move => timings.movement = elapsed,
+                Phase::Deposit => timings.deposit = elapsed,
+                Phase::Nutrients => timings.nutrients = elapsed,
+                Phase::DiffuseDecay => timings.diffuse_decay = elapsed,
+            }
+        }
+        Ok(timings)
+    }
+}
 
-    let kernel = pro_que
-        .kernel_builder("all")
-        .arg(&buffer)
-        .arg(MAX_SIZE_X)
-        .arg(MAX_SIZE_Y)
-        .arg(settings.trail_diffuse)
-        .arg(settings.trail_decay)
-        .build()?;
+/// OpenCL state kept across steps: the program is compiled once, agents,
+/// trail channels, nutrients and walls live in device buffers sized for one
+/// world and a whole step runs on the device
+pub struct GpuBackend {
+    pipeline: Precisions,
+}
 
-    unsafe {
-        kernel.enq()?;
+enum Precisions {
+    Single(Pipeline<f32>),
+    Double(Pipeline<f64>),
+}
+
+impl GpuBackend {
+    /// Compile the kernels and allocate buffers for the world of `settings`
+    /// on `device`, or the first device found. Devices without double
+    /// support get single precision buffers
+    pub fn new(
+        device: Option<&GpuDevice>,
+        precision: Precision,
+        settings: &Settings,
+    ) -> Result<Self, GpuError> {
+        let device = match device {
+            Some(device) => device.clone(),
+            None => devices().into_iter().next().ok_or(GpuError::NoDevice)?,
+        };
+        let precision = if precision == Precision::Double && !device.supports_double() {
+            warn!("{} has no double precision, using f32", device.name);
+            Precision::Single
+        } else {
+            precision
+        };
+        let cells = (settings.size_x * settings.size_y) as usize;
+        let pipeline = match precision {
+            Precision::Single => Precisions::Single(Pipeline::new(&device, cells)?),
+            Precision::Double => Precisions::Double(Pipeline::new(&device, cells)?),
+        };
+        Ok(GpuBackend { pipeline })
+    }
+
+    /// Float type actually used on the device
+    pub fn precision(&self) -> Precision {
+        match &self.pipeline {
+            Precisions::Single(_) => Precision::Single,
+            Precisions::Double(_) => Precision::Double,
+        }
+    }
+
+    /// Replace the whole device state with the host one, agents random
+    /// generators are derived from `seed`
+    pub fn upload<H: Real>(
+        &self,
+        agents: &Agents<H>,
+        trail_maps: &[TrailMap<H>],
+        nutrient_map: &NutrientMap,
+        obstacle_map: &ObstacleMap,
+        seed: u64,
+    ) -> ocl::Result<()> {
+        match &self.pipeline {
+            Precisions::Single(pipeline) => {
+                pipeline.upload(agents, trail_maps, nutrient_map, obstacle_map, seed)
+            }
+            Precisions::Double(pipeline) => {
+                pipeline.upload(agents, trail_maps, nutrient_map, obstacle_map, seed)
+            }
+        }
+    }
+
+    pub fn upload_agents<H: Real>(
+        &self,
+        agents: &Agents<H>,
+        settings: &Settings,
+    ) -> ocl::Result<()> {
+        match &self.pipeline {
+            Precisions::Single(pipeline) => pipeline.upload_agents(agents, settings),
+            Precisions::Double(pipeline) => pipeline.upload_agents(agents, settings),
+        }
     }
 
-    buffer.read(trail_map).enq()?;
-    Ok(())
+    pub fn read_agents<H: Real>(
+        &self,
+        agents: &mut Agents<H>,
+        settings: &Settings,
+    ) -> ocl::Result<()> {
+        match &self.pipeline {
+            Precisions::Single(pipeline) => pipeline.read_agents(agents, settings),
+            Precisions::Double(pipeline) => pipeline.read_agents(agents, settings),
+        }
+    }
+
+    pub fn read_trails<H: Real>(&self, trail_maps: &mut [TrailMap<H>]) -> ocl::Result<()> {
+        match &self.pipeline {
+            Precisions::Single(pipeline) => pipeline.read_trails(trail_maps),
+            Precisions::Double(pipeline) => pipeline.read_trails(trail_maps),
+        }
+    }
+
+    /// Run one phase alone on the device state, to check it against the CPU
+    pub fn run_phase(&self, phase: Phase, settings: &Settings) -> ocl::Result<()> {
+        match &self.pipeline {
+            Precisions::Single(pipeline) => pipeline.run_phase(phase, settings),
+            Precisions::Double(pipeline) => pipeline.run_phase(phase, settings),
+        }
+    }
+
+    /// Run all steps once on the device, timing each phase when `timed`,
+    /// which keeps the device waiting between phases
+    pub fn step(&self, settings: &Settings, timed: bool) -> ocl::Result<StepTimings> {
+        match &self.pipeline {
+            Precisions::Single(pipeline) => pipeline.step(settings, timed),
+            Precisions::Double(pipeline) => pipeline.step(settings, timed),
+        }
+    }
 }
@@ -1,152 +1,643 @@
+use std::path::Path;
+
 use egui::ColorImage;
 
-use crate::{
+#[cfg(feature = "opencl")]
+use srane::gpu::GpuDevice;
+use srane::{
     config::{
-        Settings, MAX_AGENT_N, MAX_AGENT_SPEED, MAX_AGENT_TURN, MAX_SENSOR_ANGLE,
-        MAX_SENSOR_DISTANCE, MAX_SENSOR_SIZE, MAX_SIZE_X, MAX_SIZE_Y, MAX_TRAIL_DECAY,
-        MAX_TRAIL_DIFFUSE, MAX_TRAIL_WEIGHT,
-    },
-    gpu::{gpu_all, gpu_decay, gpu_diffuse, gpu_move},
-    simulation::{
-        cpu_deposit, cpu_diffuse_decay, cpu_move, cpu_sense_rotate, Agent, Agents, TrailMap,
+        Backend, BoundaryMode, DecayMode, DepositMode, DiffusionKernel, MovementMode, Precision,
+        Settings, Spawn, SteeringRule, MAX_AGENT_N, MAX_AGENT_SPEED, MAX_AGENT_TURN,
+        MAX_ATTRACTION, MAX_DEPOSIT_CAP, MAX_ENERGY, MAX_FAN_SENSORS, MAX_FOOD_GAIN,
+        MAX_FOOD_RADIUS, MAX_FOOD_STRENGTH, MAX_KERNEL_SIGMA, MAX_MOVE_COST, MAX_SEED,
+        MAX_SENSOR_ANGLE, MAX_SENSOR_DISTANCE, MAX_SENSOR_SIZE, MAX_SIZE_X, MAX_SIZE_Y,
+        MAX_SOFTMAX_TEMPERATURE, MAX_SPECIES, MAX_STEERING_GAIN, MAX_TRAIL_DECAY,
+        MAX_TRAIL_DECAY_RATE, MAX_TRAIL_DIFFUSE, MAX_TRAIL_GAIN, MAX_TRAIL_HALF_LIFE,
+        MAX_TRAIL_WEIGHT, MAX_WALL_RADIUS,
     },
+    preset::{self, Preset},
+    render, Simulation,
 };
 
+/// What a click on the map does
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Tool {
+    Food,
+    Wall,
+    Erase,
+}
+
 pub struct MyEguiApp {
-    // Simulation settings
-    settings: Settings,
+    // Simulation state
+    simulation: Simulation,
     // Buffer var
     textury: Option<egui::TextureHandle>,
     image: ColorImage,
-    trail_map: TrailMap,
-    agents: Agents,
+    // Edition var
+    size_x: u32,
+    size_y: u32,
+    resample: bool,
+    species_selected: usize,
+    tool: Tool,
+    food_path: String,
+    food_status: String,
+    walls_path: String,
+    walls_status: String,
+    presets: Vec<Preset>,
+    preset_selected: usize,
+    preset_path: String,
+    preset_status: String,
     // State var
     running: bool,
+    parallel: bool,
+    perf_open: bool,
     gpu: bool,
+    #[cfg(feature = "opencl")]
+    gpu_devices: Vec<GpuDevice>,
+    #[cfg(feature = "opencl")]
+    gpu_device_selected: usize,
+    gpu_status: String,
 }
 
 impl MyEguiApp {
-    pub fn new(_cc: &eframe::CreationContext<'_>) -> Self {
-        let settings = Settings::default();
-        let mut agents = Vec::new();
-        agents.resize_with(MAX_AGENT_N as usize, || {
-            Agent::new(settings.size_x, settings.size_y)
-        });
+    pub fn new(
+        _cc: &eframe::CreationContext<'_>,
+        simulation: Simulation,
+        backend: Backend,
+        running: bool,
+    ) -> Self {
+        #[cfg(feature = "opencl")]
+        let gpu_devices = srane::gpu::devices();
+        #[cfg(feature = "opencl")]
+        let (gpu, gpu_status) = if backend == Backend::OpenCl && gpu_devices.is_empty() {
+            tracing::warn!("no OpenCL device found, using the cpu backend");
+            (
+                false,
+                String::from("No OpenCL device found, running on the CPU"),
+            )
+        } else {
+            (backend == Backend::OpenCl, String::new())
+        };
+        #[cfg(not(feature = "opencl"))]
+        let (gpu, gpu_status) = (backend == Backend::OpenCl, String::new());
+        let (size_x, size_y) = simulation.size();
         MyEguiApp {
-            settings,
+            simulation,
             textury: None,
-            image: ColorImage::new(
-                [MAX_SIZE_X as usize, MAX_SIZE_Y as usize],
-                egui::Color32::DARK_GRAY,
-            ),
-            trail_map: vec![0.0; (MAX_SIZE_X * MAX_SIZE_Y) as usize],
-            agents,
-            running: true,
-            gpu: false,
+            image: ColorImage::new([size_x as usize, size_y as usize], egui::Color32::DARK_GRAY),
+            size_x,
+            size_y,
+            resample: true,
+            species_selected: 0,
+            tool: Tool::Food,
+            food_path: String::from("food.png"),
+            food_status: String::new(),
+            walls_path: String::from("walls.png"),
+            walls_status: String::new(),
+            presets: preset::builtin(),
+            preset_selected: 0,
+            preset_path: String::from("preset.toml"),
+            preset_status: String::new(),
+            running,
+            parallel: backend == Backend::CpuParallel,
+            perf_open: false,
+            gpu,
+            #[cfg(feature = "opencl")]
+            gpu_devices,
+            #[cfg(feature = "opencl")]
+            gpu_device_selected: 0,
+            gpu_status,
         }
     }
 
     fn left_panel(&mut self, ctx: &egui::Context) {
+        let mut respawn = None;
+        let mut restart = false;
+        let mut resize = false;
+        let mut clear_food = false;
+        let mut load_food = false;
+        let mut clear_walls = false;
+        let mut load_walls = false;
+        #[cfg(feature = "opencl")]
+        let gpu_device_selected = self.gpu_device_selected;
+        let mut precision = self.simulation.precision();
+        #[cfg(feature = "opencl")]
+        let gpu_precision = self.simulation.gpu_precision();
         egui::SidePanel::new(egui::panel::Side::Left, "left_panel").show(ctx, |ui| {
-            ui.label("Simulation Settings");
-            ui.add(egui::Slider::new(&mut self.settings.size_x, 1..=MAX_SIZE_X).text("size_x"));
-            ui.add(egui::Slider::new(&mut self.settings.size_y, 1..=MAX_SIZE_Y).text("size_y"));
-            if self.running {
-                if ui.add(egui::Button::new("Pause")).clicked() {
-                    self.running = false
-                };
-            } else if ui.add(egui::Button::new("Run")).clicked() {
-                self.running = true
-            };
-            if ui.add(egui::Button::new("Reset")).clicked() {
-                self.settings = Settings::default()
-            };
-            // ui.checkbox(&mut self.gpu, "Enable GPU render");
-            ui.add_enabled(
-                false,
-                egui::Checkbox::new(&mut self.gpu, "Enable GPU render"),
-            );
-            ui.separator();
-            ui.label("Agents Settings");
-            ui.add(egui::Slider::new(&mut self.settings.agent_n, 1..=MAX_AGENT_N).text("agent_n"));
-            ui.add(
-                egui::Slider::new(&mut self.settings.agent_speed, 0.0..=MAX_AGENT_SPEED)
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                let settings = self.simulation.settings_mut();
+                ui.label("Simulation Settings");
+                ui.add(egui::Slider::new(&mut self.size_x, 1..=MAX_SIZE_X).text("size_x"));
+                ui.add(egui::Slider::new(&mut self.size_y, 1..=MAX_SIZE_Y).text("size_y"));
+                ui.horizontal(|ui| {
+                    if ui.add(egui::Button::new("Resize")).clicked() {
+                        resize = true;
+                    };
+                    ui.checkbox(&mut self.resample, "resample");
+                });
+                ui.horizontal(|ui| {
+                    ui.add(egui::DragValue::new(&mut settings.seed).range(0..=MAX_SEED));
+                    // Dragged through f64, the top of the range rounds past it
+                    settings.seed = settings.seed.min(MAX_SEED);
+                    ui.label("seed");
+                    if ui.add(egui::Button::new("New")).clicked() {
+                        settings.seed = rand::random::<u32>().into();
+                        restart = true;
+                    };
+                });
+                if self.running {
+                    if ui.add(egui::Button::new("Pause")).clicked() {
+                        self.running = false
+                    };
+                } else if ui.add(egui::Button::new("Run")).clicked() {
+                    self.running = true
+                };
+                egui::ComboBox::from_label("boundary")
+                    .selected_text(settings.boundary.to_string())
+                    .show_ui(ui, |ui| {
+                        for boundary in BoundaryMode::ALL {
+                            ui.selectable_value(
+                                &mut settings.boundary,
+                                boundary,
+                                boundary.to_string(),
+                            );
+                        }
+                    });
+                if ui.add(egui::Button::new("Reset")).clicked() {
+                    *settings = Settings::default()
+                };
+                if ui.add(egui::Button::new("Restart")).clicked() {
+                    restart = true;
+                };
+                ui.checkbox(&mut self.parallel, "Multithreaded CPU");
+                ui.checkbox(&mut self.perf_open, "Performance");
+                egui::ComboBox::from_label("precision")
+                    .selected_text(precision.to_string())
+                    .show_ui(ui, |ui| {
+                        for value in Precision::ALL {
+                            ui.selectable_value(&mut precision, value, value.to_string());
+                        }
+                    });
+                #[cfg(feature = "opencl")]
+                {
+                    ui.add_enabled(
+                        !self.gpu_devices.is_empty(),
+                        egui::Checkbox::new(&mut self.gpu, "Enable GPU render"),
+                    );
+                    if let Some(device) = self.gpu_devices.get(self.gpu_device_selected) {
+                        egui::ComboBox::from_label("device")
+                            .selected_text(&device.name)
+                            .show_ui(ui, |ui| {
+                                for (i, device) in self.gpu_devices.iter().enumerate() {
+                                    ui.selectable_value(
+                                        &mut self.gpu_device_selected,
+                                        i,
+                                        &device.name,
+                                    );
+                                }
+                            });
+                    }
+                    if let Some(used) = gpu_precision.filter(|&used| used != precision) {
+                        ui.colored_label(
+                            egui::Color32::YELLOW,
+                            format!("Running in {used}, the device has no double precision"),
+                        );
+                    }
+                }
+                #[cfg(not(feature = "opencl"))]
+                ui.add_enabled(
+                    false,
+                    egui::Checkbox::new(&mut self.gpu, "Enable GPU render"),
+                );
+                if !self.gpu_status.is_empty() {
+                    ui.colored_label(egui::Color32::YELLOW, &self.gpu_status);
+                }
+                ui.separator();
+                ui.label("Species Settings");
+                ui.add(
+                    egui::Slider::new(&mut settings.species_n, 1..=MAX_SPECIES as u8)
+                        .text("species_n"),
+                );
+                self.species_selected = self.species_selected.min(settings.species_n as usize - 1);
+                ui.add(
+                    egui::Slider::new(
+                        &mut self.species_selected,
+                        0..=settings.species_n as usize - 1,
+                    )
+                    .text("edited species"),
+                );
+                let selected = self.species_selected;
+                ui.horizontal(|ui| {
+                    ui.color_edit_button_srgb(&mut settings.species[selected].color);
+                    ui.label("color");
+                });
+                for other in 0..settings.species_n as usize {
+                    ui.add(
+                        egui::Slider::new(
+                            &mut settings.species[selected].attraction[other],
+                            -MAX_ATTRACTION..=MAX_ATTRACTION,
+                        )
+                        .text(format!("attraction to {other}")),
+                    );
+                }
+                if ui.add(egui::Button::new("Default")).clicked() {
+                    settings.default_species(selected)
+                };
+                ui.separator();
+                ui.label("Agents Settings");
+                ui.add(egui::Slider::new(&mut settings.agent_n, 1..=MAX_AGENT_N).text("agent_n"));
+                egui::ComboBox::from_label("movement")
+                    .selected_text(settings.movement.to_string())
+                    .show_ui(ui, |ui| {
+                        for movement in MovementMode::ALL {
+                            ui.selectable_value(
+                                &mut settings.movement,
+                                movement,
+                                movement.to_string(),
+                            );
+                        }
+                    });
+                ui.add(
+                    egui::Slider::new(
+                        &mut settings.species[selected].agent_speed,
+                        0.0..=MAX_AGENT_SPEED,
+                    )
                     .text("agent_speed"),
-            );
-            ui.add(
-                egui::Slider::new(&mut self.settings.agent_turn, 0.0..=MAX_AGENT_TURN)
+                );
+                ui.add(
+                    egui::Slider::new(
+                        &mut settings.species[selected].agent_turn,
+                        0.0..=MAX_AGENT_TURN,
+                    )
                     .text("agent_turn"),
-            );
-            if ui.add(egui::Button::new("Default")).clicked() {
-                self.settings.default_agents()
-            };
+                );
+                if ui.add(egui::Button::new("Default")).clicked() {
+                    settings.default_agents(selected)
+                };
 
-            ui.separator();
-            ui.label("Spawn Settings");
-            if ui.add(egui::Button::new("Random Agent")).clicked() {
-                let mut agents = Vec::new();
-                agents.resize_with(MAX_AGENT_N as usize, || {
-                    Agent::new(self.settings.size_x, self.settings.size_y)
-                });
-                self.agents = agents;
-            };
-            ui.add(
-                egui::Slider::new(&mut self.settings.spawn_radius, 0_f64..=MAX_SIZE_Y as f64)
-                    .text("spawn_radius"),
-            );
+                ui.separator();
+                ui.label("Spawn Settings");
+                if ui.add(egui::Button::new("Random Agent")).clicked() {
+                    respawn = Some(Spawn::Random);
+                };
+                ui.add(
+                    egui::Slider::new(&mut settings.spawn_radius, 0_f64..=MAX_SIZE_Y as f64)
+                        .text("spawn_radius"),
+                );
 
-            if ui.add(egui::Button::new("Random Circle Agent")).clicked() {
-                let mut agents = Vec::new();
-                agents.resize_with(MAX_AGENT_N as usize, || Agent::new_circle(&self.settings));
-                self.agents = agents;
-            };
-            if ui.add(egui::Button::new("Random Star Agent")).clicked() {
-                let mut agents = Vec::new();
-                agents.resize_with(MAX_AGENT_N as usize, || Agent::new_star(&self.settings));
-                self.agents = agents;
-            };
-            ui.separator();
-            ui.label("Sensor Settings");
-            ui.add(
-                egui::Slider::new(&mut self.settings.sensor_angle, 0.0..=MAX_SENSOR_ANGLE)
+                if ui.add(egui::Button::new("Random Circle Agent")).clicked() {
+                    respawn = Some(Spawn::Circle);
+                };
+                if ui.add(egui::Button::new("Random Star Agent")).clicked() {
+                    respawn = Some(Spawn::Star);
+                };
+                ui.separator();
+                ui.label("Sensor Settings");
+                ui.add(
+                    egui::Slider::new(
+                        &mut settings.species[selected].sensor_angle,
+                        0.0..=MAX_SENSOR_ANGLE,
+                    )
                     .text("sensor_angle"),
-            );
-            ui.add(
-                egui::Slider::new(
-                    &mut self.settings.sensor_distance,
-                    0.0..=MAX_SENSOR_DISTANCE,
-                )
-                .text("sensor_distance"),
-            );
-            ui.add(
-                egui::Slider::new(&mut self.settings.sensor_size, 0..=MAX_SENSOR_SIZE)
+                );
+                ui.add(
+                    egui::Slider::new(
+                        &mut settings.species[selected].sensor_distance,
+                        0.0..=MAX_SENSOR_DISTANCE,
+                    )
+                    .text("sensor_distance"),
+                );
+                ui.add(
+                    egui::Slider::new(
+                        &mut settings.species[selected].sensor_size,
+                        0..=MAX_SENSOR_SIZE,
+                    )
                     .text("sensor_size"),
-            );
-            if ui.add(egui::Button::new("Default")).clicked() {
-                self.settings.default_sensor()
-            };
-            ui.separator();
-            ui.label("Trail Settings");
-            ui.add(
-                egui::Slider::new(&mut self.settings.trail_weight, 0.0..=MAX_TRAIL_WEIGHT)
+                );
+                let steering = &mut settings.species[selected].steering;
+                egui::ComboBox::from_label("steering")
+                    .selected_text(steering.to_string())
+                    .show_ui(ui, |ui| {
+                        for rule in SteeringRule::defaults() {
+                            let selected = rule.to_string() == steering.to_string();
+                            if ui.selectable_label(selected, rule.to_string()).clicked()
+                                && !selected
+                            {
+                                *steering = rule;
+                            }
+                        }
+                    });
+                match steering {
+                    SteeringRule::Random | SteeringRule::Fixed => {}
+                    SteeringRule::Gradient { gain } => {
+                        ui.add(egui::Slider::new(gain, 0.0..=MAX_STEERING_GAIN).text("gain"));
+                    }
+                    SteeringRule::Softmax { temperature } => {
+                        ui.add(
+                            egui::Slider::new(temperature, 0.1..=MAX_SOFTMAX_TEMPERATURE)
+                                .logarithmic(true)
+                                .text("temperature"),
+                        );
+                    }
+                    SteeringRule::Fan { sensors } => {
+                        ui.add(egui::Slider::new(sensors, 2..=MAX_FAN_SENSORS).text("sensors"));
+                    }
+                }
+                if ui.add(egui::Button::new("Default")).clicked() {
+                    settings.default_sensor(selected)
+                };
+                ui.separator();
+                ui.label("Trail Settings");
+                ui.add(
+                    egui::Slider::new(
+                        &mut settings.species[selected].trail_weight,
+                        0.0..=MAX_TRAIL_WEIGHT,
+                    )
                     .text("trail_weight"),
-            );
-            ui.add(
-                egui::Slider::new(&mut self.settings.trail_decay, 0.0..=MAX_TRAIL_DECAY)
-                    .text("trail_decay"),
-            );
-            ui.add(
-                egui::Slider::new(&mut self.settings.trail_diffuse, 0.0..=MAX_TRAIL_DIFFUSE)
-                    .text("trail_diffuse"),
-            );
-            if ui.add(egui::Button::new("Default")).clicked() {
-                self.settings.default_trail()
+                );
+                egui::ComboBox::from_label("deposit")
+                    .selected_text(settings.deposit.to_string())
+                    .show_ui(ui, |ui| {
+                        for mode in DepositMode::ALL {
+                            ui.selectable_value(&mut settings.deposit, mode, mode.to_string());
+                        }
+                    });
+                if settings.deposit == DepositMode::Capped {
+                    ui.add(
+                        egui::Slider::new(&mut settings.deposit_cap, 0.0..=MAX_DEPOSIT_CAP)
+                            .text("deposit_cap"),
+                    );
+                }
+                ui.checkbox(&mut settings.bilinear, "bilinear deposit & sensing");
+                egui::ComboBox::from_label("decay_mode")
+                    .selected_text(settings.decay_mode.to_string())
+                    .show_ui(ui, |ui| {
+                        for mode in DecayMode::ALL {
+                            ui.selectable_value(&mut settings.decay_mode, mode, mode.to_string());
+                        }
+                    });
+                if matches!(settings.decay_mode, DecayMode::Linear | DecayMode::Combined) {
+                    ui.add(
+                        egui::Slider::new(&mut settings.trail_decay, 0.0..=MAX_TRAIL_DECAY)
+                            .text("trail_decay"),
+                    );
+                }
+                if matches!(
+                    settings.decay_mode,
+                    DecayMode::Multiplicative | DecayMode::Combined
+                ) {
+                    ui.add(
+                        egui::Slider::new(
+                            &mut settings.trail_decay_rate,
+                            0.0..=MAX_TRAIL_DECAY_RATE,
+                        )
+                        .text("trail_decay_rate"),
+                    );
+                }
+                if settings.decay_mode == DecayMode::Exponential {
+                    ui.add(
+                        egui::Slider::new(&mut settings.trail_half_life, 0.1..=MAX_TRAIL_HALF_LIFE)
+                            .logarithmic(true)
+                            .text("trail_half_life"),
+                    );
+                }
+                ui.add(
+                    egui::Slider::new(&mut settings.trail_diffuse, 0.0..=MAX_TRAIL_DIFFUSE)
+                        .text("trail_diffuse"),
+                );
+                egui::ComboBox::from_label("diffusion")
+                    .selected_text(settings.diffusion.to_string())
+                    .show_ui(ui, |ui| {
+                        for kernel in DiffusionKernel::defaults() {
+                            let selected = kernel.to_string() == settings.diffusion.to_string();
+                            if ui.selectable_label(selected, kernel.to_string()).clicked()
+                                && !selected
+                            {
+                                settings.diffusion = kernel;
+                            }
+                        }
+                    });
+                match &mut settings.diffusion {
+                    DiffusionKernel::Box3 | DiffusionKernel::Box5 => {}
+                    DiffusionKernel::Gaussian { sigma } => {
+                        ui.add(egui::Slider::new(sigma, 0.1..=MAX_KERNEL_SIGMA).text("sigma"));
+                    }
+                    DiffusionKernel::Anisotropic {
+                        sigma_along,
+                        sigma_across,
+                        angle,
+                    } => {
+                        ui.add(
+                            egui::Slider::new(sigma_along, 0.1..=MAX_KERNEL_SIGMA)
+                                .text("sigma_along"),
+                        );
+                        ui.add(
+                            egui::Slider::new(sigma_across, 0.1..=MAX_KERNEL_SIGMA)
+                                .text("sigma_across"),
+                        );
+                        ui.add(egui::Slider::new(angle, 0.0..=180.0).text("angle"));
+                    }
+                    DiffusionKernel::Custom { weights } => {
+                        ui.horizontal(|ui| {
+                            for side in [3, 5] {
+                                if ui
+                                    .add(egui::Button::new(format!("{side}×{side}")))
+                                    .clicked()
+                                {
+                                    *weights = vec![1.0; side * side];
+                                }
+                            }
+                        });
+                        let side = (weights.len() as f64).sqrt() as usize;
+                        egui::Grid::new("diffusion_weights").show(ui, |ui| {
+                            for (i, weight) in weights.iter_mut().enumerate() {
+                                ui.add(egui::DragValue::new(weight).speed(0.1));
+                                if (i + 1) % side.max(1) == 0 {
+                                    ui.end_row();
+                                }
+                            }
+                        });
+                    }
+                }
+                if ui.add(egui::Button::new("Default")).clicked() {
+                    settings.default_trail(selected)
+                };
+                ui.separator();
+                ui.label("Map tool");
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut self.tool, Tool::Food, "Food");
+                    ui.radio_value(&mut self.tool, Tool::Wall, "Wall");
+                    ui.radio_value(&mut self.tool, Tool::Erase, "Erase wall");
+                });
+                ui.separator();
+                ui.label("Food Settings");
+                ui.add(
+                    egui::Slider::new(&mut settings.food_radius, 1.0..=MAX_FOOD_RADIUS)
+                        .text("food_radius"),
+                );
+                ui.add(
+                    egui::Slider::new(&mut settings.food_strength, 0.0..=MAX_FOOD_STRENGTH)
+                        .text("food_strength"),
+                );
+                if ui.add(egui::Button::new("Default")).clicked() {
+                    settings.default_food()
+                };
+                if ui.add(egui::Button::new("Clear food")).clicked() {
+                    clear_food = true;
+                };
+                ui.text_edit_singleline(&mut self.food_path);
+                if ui.add(egui::Button::new("Load food image")).clicked() {
+                    load_food = true;
+                };
+                if !self.food_status.is_empty() {
+                    ui.label(&self.food_status);
+                }
+                ui.separator();
+                ui.label("Obstacle Settings");
+                ui.add(
+                    egui::Slider::new(&mut settings.wall_radius, 1.0..=MAX_WALL_RADIUS)
+                        .text("wall_radius"),
+                );
+                if ui.add(egui::Button::new("Clear walls")).clicked() {
+                    clear_walls = true;
+                };
+                ui.text_edit_singleline(&mut self.walls_path);
+                if ui.add(egui::Button::new("Load walls image")).clicked() {
+                    load_walls = true;
+                };
+                if !self.walls_status.is_empty() {
+                    ui.label(&self.walls_status);
+                }
+                ui.separator();
+                ui.label("Lifecycle Settings");
+                ui.checkbox(&mut settings.lifecycle, "lifecycle");
+                ui.add(
+                    egui::Slider::new(&mut settings.move_cost, 0.0..=MAX_MOVE_COST)
+                        .text("move_cost"),
+                );
+                ui.add(
+                    egui::Slider::new(&mut settings.food_gain, 0.0..=MAX_FOOD_GAIN)
+                        .text("food_gain"),
+                );
+                ui.add(
+                    egui::Slider::new(&mut settings.trail_gain, 0.0..=MAX_TRAIL_GAIN)
+                        .logarithmic(true)
+                        .text("trail_gain"),
+                );
+                ui.add(
+                    egui::Slider::new(&mut settings.death_energy, 0.0..=MAX_ENERGY)
+                        .text("death_energy"),
+                );
+                ui.add(
+                    egui::Slider::new(&mut settings.division_energy, 0.0..=MAX_ENERGY)
+                        .text("division_energy"),
+                );
+                ui.add(
+                    egui::Slider::new(&mut settings.max_agents, 1..=MAX_AGENT_N).text("max_agents"),
+                );
+                if ui.add(egui::Button::new("Default")).clicked() {
+                    settings.default_lifecycle()
+                };
+                ui.separator();
+                ui.label("Preset Settings");
+                egui::ComboBox::from_label("preset")
+                    .selected_text(&self.presets[self.preset_selected].name)
+                    .show_ui(ui, |ui| {
+                        for (i, preset) in self.presets.iter().enumerate() {
+                            ui.selectable_value(&mut self.preset_selected, i, &preset.name);
+                        }
+                    });
+                if ui.add(egui::Button::new("Apply preset")).clicked() {
+                    *settings = self.presets[self.preset_selected].settings.clone();
+                };
+                ui.text_edit_singleline(&mut self.preset_path);
+                ui.horizontal(|ui| {
+                    if ui.add(egui::Button::new("Save preset")).clicked() {
+                        let path = Path::new(&self.preset_path);
+                        let preset = Preset {
+                            name: String::new(),
+                            settings: settings.clone(),
+                        };
+                        self.preset_status = match preset.save(path) {
+                            Ok(()) => format!("Saved {}", path.display()),
+                            Err(e) => e.to_string(),
+                        };
+                    };
+                    if ui.add(egui::Button::new("Load preset")).clicked() {
+                        match Preset::load(Path::new(&self.preset_path)) {
+                            Ok(preset) => {
+                                *settings = preset.settings.clone();
+                                self.preset_status = format!("Loaded {}", preset.name);
+                                match self.presets.iter().position(|p| p.name == preset.name) {
+                                    Some(i) => {
+                                        self.presets[i] = preset;
+                                        self.preset_selected = i;
+                                    }
+                                    None => {
+                                        self.presets.push(preset);
+                                        self.preset_selected = self.presets.len() - 1;
+                                    }
+                                }
+                            }
+                            Err(e) => self.preset_status = e.to_string(),
+                        }
+                    };
+                });
+                if !self.preset_status.is_empty() {
+                    ui.label(&self.preset_status);
+                }
+                ui.separator();
+                if self.running {
+                    ui.add(egui::Spinner::new());
+                };
+            });
+        });
+        let settings = self.simulation.settings();
+        if resize {
+            self.simulation
+                .resize(self.size_x, self.size_y, self.resample);
+        } else if (settings.size_x, settings.size_y) != self.simulation.size() {
+            // Settings replaced by a preset or the defaults
+            let (size_x, size_y) = (settings.size_x, settings.size_y);
+            self.simulation.resize(size_x, size_y, self.resample);
+            (self.size_x, self.size_y) = self.simulation.size();
+        }
+        if restart {
+            self.simulation.reset();
+        }
+        if clear_food {
+            self.simulation.clear_food();
+        }
+        if load_food {
+            self.food_status = match self.simulation.load_food(Path::new(&self.food_path)) {
+                Ok(()) => format!("Loaded {}", self.food_path),
+                Err(e) => e.to_string(),
             };
-            ui.separator();
-            if self.running {
-                ui.add(egui::Spinner::new());
+        }
+        #[cfg(feature = "opencl")]
+        if self.gpu_device_selected != gpu_device_selected {
+            let device = self.gpu_devices[self.gpu_device_selected].clone();
+            self.simulation.set_gpu_device(Some(device));
+            self.gpu_status.clear();
+        }
+        if precision != self.simulation.precision() {
+            self.simulation.set_precision(precision);
+            #[cfg(feature = "opencl")]
+            self.gpu_status.clear();
+        }
+        if clear_walls {
+            self.simulation.clear_walls();
+        }
+        if load_walls {
+            self.walls_status = match self.simulation.load_walls(Path::new(&self.walls_path)) {
+                Ok(()) => format!("Loaded {}", self.walls_path),
+                Err(e) => e.to_string(),
             };
-        });
+        }
+        if let Some(spawn) = respawn {
+            self.simulation.settings_mut().spawn = spawn;
+            self.simulation.spawn(spawn);
+        }
     }
 
     fn central_panel(&mut self, ctx: &egui::Context) {
@@ -158,19 +649,62 @@ impl MyEguiApp {
             });
 
             texture.set(self.image.clone(), Default::default());
-            ui.image((texture.id(), texture.size_vec2()));
+            let response = ui.add(
+                egui::Image::new((texture.id(), texture.size_vec2()))
+                    .shrink_to_fit()
+                    .sense(egui::Sense::click_and_drag()),
+            );
+            if let Some(pos) = response.interact_pointer_pos() {
+                let cell = (pos - response.rect.min) / response.rect.size() * texture.size_vec2();
+                let (x, y) = (cell.x as f64, cell.y as f64);
+                match self.tool {
+                    Tool::Food if response.clicked() => self.simulation.add_food(x, y),
+                    Tool::Food => {}
+                    Tool::Wall => self.simulation.paint_wall(x, y, true),
+                    Tool::Erase => self.simulation.paint_wall(x, y, false),
+                }
+            }
         });
     }
 
+    /// Time of each phase of the last step
+    fn perf_window(&mut self, ctx: &egui::Context) {
+        let timings = *self.simulation.timings();
+        egui::Window::new("Performance")
+            .open(&mut self.perf_open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                egui::Grid::new("perf_grid").striped(true).show(ui, |ui| {
+                    for (name, time) in timings.phases() {
+                        ui.label(name);
+                        ui.label(format!("{:.2} ms", time.as_secs_f64() * 1000.0));
+                        ui.end_row();
+                    }
+                    let total = timings.total().as_secs_f64();
+                    ui.label("step");
+                    ui.label(format!("{:.2} ms", total * 1000.0));
+                    ui.end_row();
+                    if total > 0.0 {
+                        ui.label("steps/s");
+                        ui.label(format!("{:.1}", 1.0 / total));
+                        ui.end_row();
+                    }
+                });
+            });
+    }
+
     fn draw_map(&mut self) {
+        let (size_x, size_y) = self.simulation.size();
+        if self.image.size != [size_x as usize, size_y as usize] {
+            self.image =
+                ColorImage::new([size_x as usize, size_y as usize], egui::Color32::DARK_GRAY);
+        }
         let current = self.image.as_raw_mut();
 
-        for y in 0..self.settings.size_y {
-            for x in 0..self.settings.size_x {
-                let value = self.trail_map[(x + MAX_SIZE_X * y) as usize];
-                current
-                    [((x + MAX_SIZE_X * y) * 4) as usize..((x + MAX_SIZE_X * y) * 4 + 3) as usize]
-                    .copy_from_slice(&[value as u8; 3]);
+        for y in 0..size_y {
+            for x in 0..size_x {
+                current[((x + size_x * y) * 4) as usize..((x + size_x * y) * 4 + 3) as usize]
+                    .copy_from_slice(&render::cell_color(&self.simulation, x, y));
             }
         }
     }
@@ -179,24 +713,26 @@ impl MyEguiApp {
 impl eframe::App for MyEguiApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         if self.running {
+            #[cfg(feature = "opencl")]
             if self.gpu {
-                cpu_sense_rotate(&self.trail_map, &mut self.agents, &self.settings);
-
-                gpu_move(&mut self.agents, &self.settings).unwrap();
-
-                cpu_deposit(&self.agents, &mut self.trail_map, &self.settings);
-
-                // Diffuse & Decay
-                gpu_all(&mut self.trail_map, &self.settings).unwrap();
+                // Timing phases holds the device back, only for the window
+                self.simulation.set_gpu_timed(self.perf_open);
+                if let Err(e) = self.simulation.step_gpu() {
+                    tracing::warn!("{e}, falling back to the cpu backend");
+                    self.gpu_status = format!("GPU disabled: {e}");
+                    self.gpu = false;
+                    self.simulation.step();
+                }
+            } else if self.parallel {
+                self.simulation.step_parallel();
             } else {
-                cpu_sense_rotate(&self.trail_map, &mut self.agents, &self.settings);
-
-                cpu_move(&mut self.agents, &self.settings);
-
-                cpu_deposit(&self.agents, &mut self.trail_map, &self.settings);
-
-                // Diffuse
-                cpu_diffuse_decay(&mut self.trail_map, &self.settings);
+                self.simulation.step();
+            }
+            #[cfg(not(feature = "opencl"))]
+            if self.parallel {
+                self.simulation.step_parallel();
+            } else {
+                self.simulation.step();
             }
         }
 
@@ -205,5 +741,7 @@ impl eframe::App for MyEguiApp {
         self.left_panel(ctx);
 
         self.central_panel(ctx);
+
+        self.perf_window(ctx);
     }
 }
@@ -0,0 +1,70 @@
+use std::path::Path;
+
+use crate::config::Settings;
+
+/// Chemoattractant emitted in every trail channel each step
+pub type NutrientMap = Vec<f64>;
+/// Walls agents cannot enter and trail cannot diffuse through
+pub type ObstacleMap = Vec<bool>;
+
+/// Grey levels in `[0, 1]` of an image stretched over the world, laid out
+/// like the trail maps
+pub fn load_grey(path: &Path, settings: &Settings) -> Result<Vec<f64>, image::ImageError> {
+    let image = image::open(path)?.into_luma8();
+    let mut layer = vec![0.0; (settings.size_x * settings.size_y) as usize];
+    if image.width() == 0 || image.height() == 0 {
+        return Ok(layer);
+    }
+    for y in 0..settings.size_y {
+        for x in 0..settings.size_x {
+            let pixel = image.get_pixel(
+                (x as u64 * image.width() as u64 / settings.size_x as u64) as u32,
+                (y as u64 * image.height() as u64 / settings.size_y as u64) as u32,
+            );
+            layer[(x + settings.size_x * y) as usize] = pixel.0[0] as f64 / 255.0;
+        }
+    }
+    Ok(layer)
+}
+
+/// Cells of the world within `radius` of `(x, y)`, as layer indexes
+pub fn disc(x: f64, y: f64, radius: f64, settings: &Settings) -> impl Iterator<Item = usize> {
+    let (size_x, size_y) = (settings.size_x as f64, settings.size_y as f64);
+    let stride = settings.size_x;
+    let x_range = (x - radius).floor().max(0.0) as u32..(x + radius).ceil().min(size_x) as u32;
+    let y_range = (y - radius).floor().max(0.0) as u32..(y + radius).ceil().min(size_y) as u32;
+    y_range.flat_map(move |cell_y| {
+        x_range.clone().filter_map(move |cell_x| {
+            let (dx, dy) = (cell_x as f64 + 0.5 - x, cell_y as f64 + 0.5 - y);
+            (dx * dx + dy * dy <= radius * radius).then_some((cell_x + stride * cell_y) as usize)
+        })
+    })
+}
+
+/// Layer of `from` cells laid out again for `to` cells, either stretched
+/// with nearest neighbour sampling or cropped and padded with the default
+/// value from the top left corner
+pub fn resize<T: Copy + Default>(
+    layer: &[T],
+    from: (u32, u32),
+    to: (u32, u32),
+    resample: bool,
+) -> Vec<T> {
+    let mut resized = vec![T::default(); (to.0 * to.1) as usize];
+    for y in 0..to.1 {
+        for x in 0..to.0 {
+            let (source_x, source_y) = if resample {
+                (
+                    (x as u64 * from.0 as u64 / to.0 as u64) as u32,
+                    (y as u64 * from.1 as u64 / to.1 as u64) as u32,
+                )
+            } else if x < from.0 && y < from.1 {
+                (x, y)
+            } else {
+                continue;
+            };
+            resized[(x + to.0 * y) as usize] = layer[(source_x + from.0 * source_y) as usize];
+        }
+    }
+    resized
+}
@@ -0,0 +1,16 @@
+//! Headless agent simulation core, usable without any GUI
+//!
+//! The [`Simulation`] type owns agents, trail map and settings and runs the
+//! sense, rotate, move, deposit, diffuse and decay steps.
+
+pub mod cli;
+pub mod config;
+#[cfg(feature = "opencl")]
+pub mod gpu;
+pub mod layer;
+pub mod preset;
+pub mod render;
+pub mod simulation;
+
+pub use config::Settings;
+pub use simulation::Simulation;
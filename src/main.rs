@@ -1,17 +1,53 @@
+use clap::Parser;
 use gui::MyEguiApp;
+use srane::{
+    cli::{BackendArgs, SettingsArgs},
+    Simulation,
+};
+use tracing::error;
 
-mod config;
-mod gpu;
+// The whole binary needs the gui feature, see `required-features`
 mod gui;
-mod simulation;
+
+/// Agent simulation renderer
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    #[command(flatten)]
+    settings: SettingsArgs,
+    #[command(flatten)]
+    backend: BackendArgs,
+    /// Start with the simulation paused
+    #[arg(long)]
+    paused: bool,
+}
 
 fn main() -> eframe::Result<()> {
     tracing_subscriber::fmt::init();
 
+    let cli = Cli::parse();
+    let settings = match cli.settings.settings() {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    let backend = cli.backend.backend();
+
+    let mut simulation = Simulation::new(settings);
+    simulation.set_precision(cli.backend.precision);
+    if let Err(e) = cli.settings.load_layers(&mut simulation) {
+        error!("{e}");
+        std::process::exit(1);
+    }
+    let running = !cli.paused;
+
     let options = eframe::NativeOptions::default();
     eframe::run_native(
         "Srane Render",
         options,
-        Box::new(|cc| Ok(Box::new(MyEguiApp::new(cc)))),
+        Box::new(move |cc| Ok(Box::new(MyEguiApp::new(cc, simulation, backend, running)))),
     )
 }
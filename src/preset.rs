@@ -0,0 +1,211 @@
+use std::{fmt, fs, io, path::Path};
+
+use crate::config::{
+    DecayMode, DepositMode, DiffusionKernel, MovementMode, Settings, Species, SteeringRule,
+};
+
+/// Named set of settings, either bundled or read from a file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Preset {
+    pub name: String,
+    pub settings: Settings,
+}
+
+#[derive(Debug)]
+pub enum PresetError {
+    Io(io::Error),
+    Toml(toml::de::Error),
+    TomlWrite(toml::ser::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresetError::Io(e) => write!(f, "preset io error: {e}"),
+            PresetError::Toml(e) => write!(f, "invalid TOML preset: {e}"),
+            PresetError::TomlWrite(e) => write!(f, "cannot write TOML preset: {e}"),
+            PresetError::Json(e) => write!(f, "invalid JSON preset: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for PresetError {}
+
+impl From<io::Error> for PresetError {
+    fn from(e: io::Error) -> Self {
+        PresetError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for PresetError {
+    fn from(e: toml::de::Error) -> Self {
+        PresetError::Toml(e)
+    }
+}
+
+impl From<toml::ser::Error> for PresetError {
+    fn from(e: toml::ser::Error) -> Self {
+        PresetError::TomlWrite(e)
+    }
+}
+
+impl From<serde_json::Error> for PresetError {
+    fn from(e: serde_json::Error) -> Self {
+        PresetError::Json(e)
+    }
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+}
+
+impl Preset {
+    /// Read a preset file, JSON if the extension is `.json` and TOML otherwise,
+    /// missing fields take their default value and the others are clamped to
+    /// the supported range
+    pub fn load(path: &Path) -> Result<Preset, PresetError> {
+        let content = fs::read_to_string(path)?;
+        let mut settings: Settings = if is_json(path) {
+            serde_json::from_str(&content)?
+        } else {
+            toml::from_str(&content)?
+        };
+        settings.clamp_to_max();
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok(Preset { name, settings })
+    }
+
+    /// Write the preset settings clamped to the supported range, which TOML
+    /// can always hold, format chosen like in [`Preset::load`]
+    pub fn save(&self, path: &Path) -> Result<(), PresetError> {
+        let mut settings = self.settings.clone();
+        settings.clamp_to_max();
+        let content = if is_json(path) {
+            serde_json::to_string_pretty(&settings)?
+        } else {
+            toml::to_string_pretty(&settings)?
+        };
+        fs::write(path, content)?;
+        Ok(())
+    }
+}
+
+/// Bundled parameter sets from the literature
+///
+/// Jones 2010 variants follow the SA/RA pairs of the paper with a 9 pixels
+/// sensor offset, its fixed rotation, one agent per cell and its additive
+/// deposit decaying by a tenth each step, Sage Jenson styles are
+/// approximations of the published renders.
+pub fn builtin() -> Vec<Preset> {
+    let preset = |name: &str, settings: Settings| Preset {
+        name: name.to_owned(),
+        settings,
+    };
+    let jones = |sensor_angle: f64, agent_turn: f64| Settings {
+        movement: MovementMode::Exclusive,
+        deposit: DepositMode::Additive,
+        decay_mode: DecayMode::Multiplicative,
+        trail_decay_rate: 0.1,
+        trail_diffuse: 1.0,
+        ..Settings::single(Species {
+            agent_speed: 1.0,
+            agent_turn,
+            sensor_angle,
+            sensor_distance: 9.0,
+            sensor_size: 1,
+            steering: SteeringRule::Fixed,
+            trail_weight: 25.0,
+            ..Species::default()
+        })
+    };
+    vec![
+        preset("Default", Settings::default()),
+        preset("Jones 2010 - SA 22.5 RA 45", jones(22.5, 45.0)),
+        preset("Jones 2010 - SA 45 RA 45", jones(45.0, 45.0)),
+        preset("Jones 2010 - SA 90 RA 45", jones(90.0, 45.0)),
+        preset("Jones 2010 - SA 22.5 RA 22.5", jones(22.5, 22.5)),
+        preset(
+            "Jenson - fine filaments",
+            Settings {
+                agent_n: 100000,
+                trail_decay: 4.0,
+                trail_diffuse: 0.2,
+                ..Settings::single(Species {
+                    agent_speed: 1.5,
+                    agent_turn: 25.0,
+                    sensor_angle: 25.0,
+                    sensor_distance: 8.0,
+                    sensor_size: 1,
+                    trail_weight: 120.0,
+                    ..Species::default()
+                })
+            },
+        ),
+        preset(
+            "Jenson - thick veins",
+            Settings {
+                agent_n: 200000,
+                trail_decay: 1.0,
+                trail_diffuse: 0.5,
+                ..Settings::single(Species {
+                    agent_speed: 1.0,
+                    agent_turn: 60.0,
+                    sensor_angle: 40.0,
+                    sensor_distance: 10.0,
+                    sensor_size: 2,
+                    trail_weight: 255.0,
+                    ..Species::default()
+                })
+            },
+        ),
+        preset(
+            "Jenson - cellular",
+            Settings {
+                agent_n: 150000,
+                trail_decay: 3.0,
+                trail_diffuse: 0.3,
+                ..Settings::single(Species {
+                    agent_speed: 2.0,
+                    agent_turn: 90.0,
+                    sensor_angle: 90.0,
+                    sensor_distance: 5.0,
+                    sensor_size: 1,
+                    trail_weight: 200.0,
+                    ..Species::default()
+                })
+            },
+        ),
+        preset(
+            "Jenson - soft glow",
+            Settings {
+                agent_n: 100000,
+                trail_decay: 2.0,
+                trail_diffuse: 0.6,
+                diffusion: DiffusionKernel::Gaussian { sigma: 1.5 },
+                ..Settings::single(Species {
+                    agent_speed: 1.0,
+                    agent_turn: 45.0,
+                    sensor_angle: 30.0,
+                    sensor_distance: 9.0,
+                    sensor_size: 1,
+                    trail_weight: 255.0,
+                    ..Species::default()
+                })
+            },
+        ),
+        preset("Three species", {
+            let mut settings = Settings {
+                agent_n: 60000,
+                species_n: 3,
+                ..Settings::default()
+            };
+            settings.species[0].color = [255, 196, 64];
+            settings
+        }),
+    ]
+}
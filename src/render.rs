@@ -0,0 +1,34 @@
+use crate::simulation::Simulation;
+
+/// Colour of obstacle cells
+pub const WALL_COLOR: [u8; 3] = [48, 48, 96];
+
+/// Colour of one cell of the world, species colours weighted by their trail
+/// and added together, or the wall colour
+pub fn cell_color(simulation: &Simulation, x: u32, y: u32) -> [u8; 3] {
+    let cell = (x + simulation.size().0 * y) as usize;
+    if simulation.obstacle_map()[cell] {
+        return WALL_COLOR;
+    }
+    let mut color = [0_f64; 3];
+    let channels = simulation.trail_channels();
+    for (index, species) in simulation.settings().species[..channels].iter().enumerate() {
+        let value = simulation.trail(index, cell).clamp(0.0, 255.0) / 255.0;
+        for (channel, species_channel) in color.iter_mut().zip(species.color) {
+            *channel += value * species_channel as f64;
+        }
+    }
+    color.map(|channel| channel as u8)
+}
+
+/// Packed RGB pixels of the world, row by row
+pub fn rgb_image(simulation: &Simulation) -> Vec<u8> {
+    let (size_x, size_y) = simulation.size();
+    let mut pixels = Vec::with_capacity((size_x * size_y * 3) as usize);
+    for y in 0..size_y {
+        for x in 0..size_x {
+            pixels.extend_from_slice(&cell_color(simulation, x, y));
+        }
+    }
+    pixels
+}
@@ -1,35 +1,102 @@
 use core::f64::consts::PI;
-use ocl::OclPrm;
-use rand::{thread_rng, Rng};
-use std::cmp::{max, min};
+use rand::{seq::SliceRandom, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
 use tracing::debug;
 
-use crate::config::{Settings, MAX_SIZE_X, MAX_SIZE_Y};
+use std::{
+    fmt::Debug,
+    ops::{AddAssign, Div, Mul},
+    path::Path,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
 
-#[derive(Clone, Debug, PartialEq, Default, Copy)]
-pub struct Agent {
-    pos_x: f64,
-    pos_y: f64,
-    angle: f64,
+#[cfg(feature = "opencl")]
+use crate::gpu::{GpuBackend, GpuDevice, GpuError};
+use crate::{
+    config::{
+        BoundaryMode, DepositMode, MovementMode, Precision, Settings, Spawn, Species, SteeringRule,
+        Stencil, MAX_AGENT_N, MAX_FAN_SENSORS, MAX_SIZE_X, MAX_SIZE_Y,
+    },
+    layer::{self, NutrientMap, ObstacleMap},
+};
+#[cfg(feature = "opencl")]
+use tracing::warn;
+
+/// Attempts to spawn an agent in the world outside walls before giving up
+const SPAWN_TRIES: usize = 64;
+/// Steps per cell side of the bilinear deposit, shares of a cell are whole
+/// numbers of `SUBCELL_STEPS²` so every backend sums them exactly
+pub(crate) const SUBCELL_STEPS: u32 = 64;
+/// Agents sharing one random generator in the parallel backend, fixed so the
+/// results don't depend on the number of threads
+const PAR_CHUNK: usize = 1024;
+
+/// Float type agents and trail channels are stored in, the phases compute in
+/// `f64` and round their results to it
+pub trait Real: Copy + Debug + Default + PartialEq + Send + Sync + 'static {
+    /// Precision of the type
+    const PRECISION: Precision;
+    fn from_f64(value: f64) -> Self;
+    fn to_f64(self) -> f64;
+}
+
+impl Real for f32 {
+    const PRECISION: Precision = Precision::Single;
+    fn from_f64(value: f64) -> Self {
+        value as f32
+    }
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
 }
 
-unsafe impl OclPrm for Agent {}
+impl Real for f64 {
+    const PRECISION: Precision = Precision::Double;
+    fn from_f64(value: f64) -> Self {
+        value
+    }
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Default, Copy)]
+#[repr(C)]
+pub struct Agent<R: Real = f64> {
+    pub(crate) pos_x: R,
+    pub(crate) pos_y: R,
+    pub(crate) angle: R,
+    pub(crate) species: u32,
+    /// Only used by the lifecycle, kept on the host in `f64` with every
+    /// backend
+    pub(crate) energy: f64,
+    /// Whether the last exclusive move found its destination taken
+    pub(crate) blocked: bool,
+}
 
-pub type Agents = Vec<Agent>;
-pub type TrailMap = Vec<f64>;
+pub type Agents<R = f64> = Vec<Agent<R>>;
+pub type TrailMap<R = f64> = Vec<R>;
+/// Share of each cell covered by the agents depositing in one channel, in
+/// `SUBCELL_STEPS²` units, back to zero between deposits
+pub type CoverageMap = Vec<AtomicU32>;
+/// Number of agents in each cell, counted again for each exclusive move
+pub type OccupancyMap = Vec<u32>;
 
 impl Agent {
-    pub fn new(size_x: u32, size_y: u32) -> Self {
-        let mut rng = rand::thread_rng();
+    pub fn new(size_x: u32, size_y: u32, rng: &mut impl Rng) -> Self {
         Agent {
             pos_x: rng.gen::<f64>() * size_x as f64,
             pos_y: rng.gen::<f64>() * size_y as f64,
             angle: rng.gen::<f64>() * 2_f64 * PI,
+            species: 0,
+            energy: 0.0,
+            blocked: false,
         }
     }
 
-    pub fn new_circle(settings: &Settings) -> Agent {
-        let mut rng = rand::thread_rng();
+    pub fn new_circle(settings: &Settings, rng: &mut impl Rng) -> Agent {
         let angle = rng.gen::<f64>() * 2_f64 * PI;
         let radius = rng.gen::<f64>() * settings.spawn_radius;
 
@@ -40,11 +107,13 @@ impl Agent {
             pos_x,
             pos_y,
             angle: angle + PI,
+            species: 0,
+            energy: 0.0,
+            blocked: false,
         }
     }
 
-    pub fn new_star(settings: &Settings) -> Agent {
-        let mut rng = rand::thread_rng();
+    pub fn new_star(settings: &Settings, rng: &mut impl Rng) -> Agent {
         let angle = rng.gen::<f64>() * 2_f64 * PI;
         let pos_x = settings.size_x as f64 / 2_f64;
         let pos_y = settings.size_y as f64 / 2_f64;
@@ -53,128 +122,1859 @@ impl Agent {
             pos_x,
             pos_y,
             angle,
+            species: 0,
+            energy: 0.0,
+            blocked: false,
+        }
+    }
+
+    /// Agent of `species` at `(pos_x, pos_y)` heading `angle` radians
+    pub fn at(pos_x: f64, pos_y: f64, angle: f64, species: u32) -> Agent {
+        Agent {
+            pos_x,
+            pos_y,
+            angle,
+            species,
+            ..Agent::default()
+        }
+    }
+
+    /// New agent of `species` placed as configured by `spawn`, with the
+    /// energy of a freshly divided one
+    pub fn spawn(spawn: Spawn, species: u32, settings: &Settings, rng: &mut impl Rng) -> Agent {
+        let agent = match spawn {
+            Spawn::Random => Agent::new(settings.size_x, settings.size_y, rng),
+            Spawn::Circle => Agent::new_circle(settings, rng),
+            Spawn::Star => Agent::new_star(settings, rng),
+        };
+        Agent {
+            species,
+            energy: settings.division_energy / 2.0,
+            ..agent
+        }
+    }
+}
+
+impl<R: Real> Agent<R> {
+    /// Agent rounded to the float type `R`
+    pub fn from_f64(agent: Agent) -> Self {
+        Agent {
+            pos_x: R::from_f64(agent.pos_x),
+            pos_y: R::from_f64(agent.pos_y),
+            angle: R::from_f64(agent.angle),
+            species: agent.species,
+            energy: agent.energy,
+            blocked: agent.blocked,
         }
     }
+
+    /// Agent widened to `f64`, exactly
+    pub fn to_f64(self) -> Agent {
+        Agent {
+            pos_x: self.pos_x.to_f64(),
+            pos_y: self.pos_y.to_f64(),
+            angle: self.angle.to_f64(),
+            species: self.species,
+            energy: self.energy,
+            blocked: self.blocked,
+        }
+    }
+
+    pub fn species(&self) -> u32 {
+        self.species
+    }
+
+    pub fn position(&self) -> (f64, f64) {
+        (self.pos_x.to_f64(), self.pos_y.to_f64())
+    }
+
+    pub fn angle(&self) -> f64 {
+        self.angle.to_f64()
+    }
+
+    pub fn energy(&self) -> f64 {
+        self.energy
+    }
+}
+
+/// Map a coordinate in `0..size` following the boundary mode,
+/// `None` when it falls outside with the respawn mode
+fn boundary_coord(p: isize, size: u32, boundary: BoundaryMode) -> Option<usize> {
+    let size = size as isize;
+    if (0..size).contains(&p) {
+        return Some(p as usize);
+    }
+    match boundary {
+        BoundaryMode::Clamp => Some(p.clamp(0, size - 1) as usize),
+        BoundaryMode::Wrap => Some(p.rem_euclid(size) as usize),
+        BoundaryMode::Reflect => {
+            let p = p.rem_euclid(2 * size);
+            Some(if p < size { p } else { 2 * size - 1 - p } as usize)
+        }
+        BoundaryMode::Respawn => None,
+    }
+}
+
+/// Trail map index of a cell following the boundary mode, `None` if outside
+fn boundary_cell(x: isize, y: isize, settings: &Settings) -> Option<usize> {
+    let x = boundary_coord(x, settings.size_x, settings.boundary)?;
+    let y = boundary_coord(y, settings.size_y, settings.boundary)?;
+    Some(x + settings.size_x as usize * y)
 }
 
-fn agent_sense(trail_map: &TrailMap, agent: &Agent, sensor_angle: f64, settings: &Settings) -> f64 {
+/// Wrap a position in `[0, size)`
+fn wrap_position(p: f64, size: f64) -> f64 {
+    let p = p.rem_euclid(size);
+    // rem_euclid rounds tiny negative values up to `size`
+    if p < size {
+        p
+    } else {
+        0.0
+    }
+}
+
+/// Mirror a position in `[0, size)`, returns whether it bounced
+fn reflect_position(p: &mut f64, size: f64) -> bool {
+    if *p < 0.0 {
+        *p = (-*p).min(size - 1.0).max(0.0);
+        true
+    } else if *p >= size {
+        *p = (2.0 * size - *p).max(0.0).min(size - 1.0);
+        true
+    } else {
+        false
+    }
+}
+
+/// Whether the cell holding a position inside the world is a wall
+fn is_wall(obstacle_map: &ObstacleMap, settings: &Settings, x: f64, y: f64) -> bool {
+    obstacle_map[x.floor() as usize + settings.size_x as usize * y.floor() as usize]
+}
+
+/// New agent placed as configured by `spawn`, retrying to land in the world
+/// outside walls, the last try is clamped in the world
+fn spawn_agent(
+    spawn: Spawn,
+    species: u32,
+    settings: &Settings,
+    obstacle_map: &ObstacleMap,
+    rng: &mut impl Rng,
+) -> Agent {
+    let mut agent = Agent::spawn(spawn, species, settings, rng);
+    for _ in 0..SPAWN_TRIES {
+        if agent_cell(&agent, settings).is_some_and(|cell| !obstacle_map[cell]) {
+            return agent;
+        }
+        agent = Agent::spawn(spawn, species, settings, rng);
+    }
+    // Spawn shape mostly outside the world, or a world full of walls
+    agent.pos_x = agent.pos_x.clamp(0.0, settings.size_x as f64 - 1.0);
+    agent.pos_y = agent.pos_y.clamp(0.0, settings.size_y as f64 - 1.0);
+    agent
+}
+
+/// Sum of all trail channels around a sensor, weighted by the agent species
+/// attraction, walls read as empty
+fn agent_sense<R: Real>(
+    trail_maps: &[TrailMap<R>],
+    obstacle_map: &ObstacleMap,
+    agent: &Agent,
+    sensor_angle: f64,
+    settings: &Settings,
+) -> f64 {
+    let species = &settings.species[agent.species as usize];
     let angle = agent.angle + sensor_angle.to_radians();
     let (x, y) = (
-        agent.pos_x + settings.sensor_distance * angle.cos(),
-        agent.pos_y + settings.sensor_distance * angle.sin(),
+        agent.pos_x + species.sensor_distance * angle.cos(),
+        agent.pos_y + species.sensor_distance * angle.sin(),
     );
+    let sensor_size = species.sensor_size as isize;
     let mut sum = 0.0;
 
-    for offset_x in -(settings.sensor_size as isize)..settings.sensor_size as isize {
-        for offset_y in -(settings.sensor_size as isize)..settings.sensor_size as isize {
-            let pick_x = min(
-                max(x.round() as isize + offset_x, 0),
-                MAX_SIZE_X as isize - 1,
-            ) as usize;
-            let pick_y = min(
-                max(y.round() as isize + offset_y, 0),
-                MAX_SIZE_Y as isize - 1,
-            ) as usize;
-            sum += trail_map[pick_x + MAX_SIZE_X as usize * pick_y];
+    for offset_x in -sensor_size..sensor_size {
+        for offset_y in -sensor_size..sensor_size {
+            if settings.bilinear {
+                // Window centred on the sensor, border cells count by overlap
+                for (cell_x, cell_y, share) in overlap(x + offset_x as f64, y + offset_y as f64) {
+                    sum += share
+                        * sensed_cell(trail_maps, obstacle_map, species, cell_x, cell_y, settings);
+                }
+            } else {
+                sum += sensed_cell(
+                    trail_maps,
+                    obstacle_map,
+                    species,
+                    x.round() as isize + offset_x,
+                    y.round() as isize + offset_y,
+                    settings,
+                );
+            }
         }
     }
     sum
 }
 
-/// Step 1&2: Sense & Rotate
-pub fn cpu_sense_rotate(trail_map: &TrailMap, agents: &mut Agents, settings: &Settings) {
-    let mut rng = thread_rng();
+/// Trail of one cell weighted by the species attractions, nothing in walls
+/// and outside with the respawn mode
+fn sensed_cell<R: Real>(
+    trail_maps: &[TrailMap<R>],
+    obstacle_map: &ObstacleMap,
+    species: &Species,
+    x: isize,
+    y: isize,
+    settings: &Settings,
+) -> f64 {
+    let Some(cell) = boundary_cell(x, y, settings) else {
+        return 0.0;
+    };
+    if obstacle_map[cell] {
+        return 0.0;
+    }
+    let mut sum = 0.0;
+    for (trail_map, attraction) in trail_maps.iter().zip(species.attraction) {
+        if attraction != 0.0 {
+            sum += attraction * trail_map[cell].to_f64();
+        }
+    }
+    sum
+}
 
-    for agent in &mut agents[0..settings.agent_n as usize] {
-        // Sense
-        let (weight_forward, weight_left, weight_right) = (
-            agent_sense(trail_map, agent, 0.0, settings),
-            agent_sense(trail_map, agent, settings.sensor_angle, settings),
-            agent_sense(trail_map, agent, -settings.sensor_angle, settings),
+/// Cells overlapped by a one cell square with its top left corner at
+/// `(x, y)`, and the share of the square in each
+fn overlap(x: f64, y: f64) -> [(isize, isize, f64); 4] {
+    let (cell_x, cell_y) = (x.floor(), y.floor());
+    let (share_x, share_y) = (x - cell_x, y - cell_y);
+    let (cell_x, cell_y) = (cell_x as isize, cell_y as isize);
+    [
+        (cell_x, cell_y, (1.0 - share_x) * (1.0 - share_y)),
+        (cell_x + 1, cell_y, share_x * (1.0 - share_y)),
+        (cell_x, cell_y + 1, (1.0 - share_x) * share_y),
+        (cell_x + 1, cell_y + 1, share_x * share_y),
+    ]
+}
+
+/// Sense around one agent and rotate it
+fn sense_rotate_agent<R: Real>(
+    trail_maps: &[TrailMap<R>],
+    obstacle_map: &ObstacleMap,
+    agent: &mut Agent,
+    settings: &Settings,
+    rng: &mut impl Rng,
+) {
+    let species = &settings.species[agent.species as usize];
+    // Sense, from right to left
+    let sensors = species.steering.sensors();
+    let mut weights = [0.0; MAX_FAN_SENSORS as usize];
+    for (sensor, weight) in weights[..sensors].iter_mut().enumerate() {
+        *weight = agent_sense(
+            trail_maps,
+            obstacle_map,
+            agent,
+            sensor_offset(sensor, sensors, species.sensor_angle),
+            settings,
         );
-        let random_steer_strength = rng.gen::<f64>();
+    }
+    let random = rng.gen::<f64>();
+
+    // Rotate
+    agent.angle += steer(species, &weights[..sensors], random).to_radians();
+}
 
-        // Rotate
-        // Keep forward
-        if weight_forward > weight_left && weight_forward > weight_right {
-            // Nothing to do
+/// Angle of sensor number `sensor` of `sensors` spread over
+/// `±sensor_angle`, from right to left
+fn sensor_offset(sensor: usize, sensors: usize, sensor_angle: f64) -> f64 {
+    -sensor_angle + 2.0 * sensor_angle * sensor as f64 / (sensors - 1) as f64
+}
+
+/// Turn in degrees of the species steering rule given the weights sensed
+/// from right to left and a uniform random number in [0, 1)
+fn steer(species: &Species, weights: &[f64], random: f64) -> f64 {
+    let turn = species.agent_turn;
+    // The other rules read three sensors
+    let three = || (weights[0], weights[1], weights[2]);
+    match species.steering {
+        SteeringRule::Random => {
+            let (right, forward, left) = three();
+            // Keep forward
+            if forward > left && forward > right {
+                0.0
+            }
+            // Random turn
+            else if forward < left && forward < right {
+                (random - 0.5) * 2.0 * turn
+            }
+            // Turn right
+            else if right > left {
+                -random * turn
+            }
+            // Turn left
+            else if left > right {
+                random * turn
+            } else {
+                0.0
+            }
         }
-        // Random turn
-        else if weight_forward < weight_left && weight_forward < weight_right {
-            agent.angle +=
-                ((random_steer_strength - 0.5) * 2_f64 * settings.agent_turn).to_radians();
+        SteeringRule::Fixed => {
+            let (right, forward, left) = three();
+            if forward > left && forward > right {
+                0.0
+            } else if forward < left && forward < right {
+                if random < 0.5 {
+                    -turn
+                } else {
+                    turn
+                }
+            } else if right > left {
+                -turn
+            } else if left > right {
+                turn
+            } else {
+                0.0
+            }
+        }
+        SteeringRule::Gradient { gain } => {
+            let (right, forward, left) = three();
+            let total = right.abs() + forward.abs() + left.abs();
+            if total > 0.0 {
+                (gain * (left - right) / total).clamp(-1.0, 1.0) * turn
+            } else {
+                0.0
+            }
         }
-        // Turn right
-        else if weight_right > weight_left {
-            agent.angle -= (random_steer_strength * settings.agent_turn).to_radians();
+        SteeringRule::Softmax { temperature } => {
+            let (right, forward, left) = three();
+            // Shifted by the strongest so the exponentials stay finite
+            let strongest = right.max(forward).max(left);
+            let chances = [right, forward, left].map(|w| ((w - strongest) / temperature).exp());
+            let mut pick = random * chances.iter().sum::<f64>();
+            let mut chosen = 2;
+            for (sensor, chance) in chances.into_iter().enumerate() {
+                if pick < chance {
+                    chosen = sensor;
+                    break;
+                }
+                pick -= chance;
+            }
+            (chosen as f64 - 1.0) * turn
         }
-        // Turn left
-        else if weight_left > weight_right {
-            agent.angle += (random_steer_strength * settings.agent_turn).to_radians();
+        SteeringRule::Fan { .. } => {
+            // Strongest sensor, the one nearest forward on ties
+            let offset = |sensor| sensor_offset(sensor, weights.len(), species.sensor_angle);
+            let mut best = 0;
+            for sensor in 1..weights.len() {
+                if weights[sensor] > weights[best]
+                    || weights[sensor] == weights[best] && offset(sensor).abs() < offset(best).abs()
+                {
+                    best = sensor;
+                }
+            }
+            offset(best).clamp(-turn, turn)
         }
     }
 }
 
-/// Step 3: Move
-pub fn cpu_move(agents: &mut Agents, settings: &Settings) {
-    let mut rng = thread_rng();
+/// Step 1&2: Sense & Rotate
+pub fn cpu_sense_rotate<R: Real>(
+    trail_maps: &[TrailMap<R>],
+    obstacle_map: &ObstacleMap,
+    agents: &mut Agents<R>,
+    settings: &Settings,
+    rng: &mut impl Rng,
+) {
     for agent in &mut agents[0..settings.agent_n as usize] {
-        agent.pos_x += agent.angle.cos() * settings.agent_speed;
-        agent.pos_y += agent.angle.sin() * settings.agent_speed;
-
-        // Check Collision
-        if agent.pos_x < 0.0
-            || agent.pos_x >= settings.size_x as f64
-            || agent.pos_y < 0.0
-            || agent.pos_y >= settings.size_y as f64
-        {
-            debug!(
-                "Bonk {} {} [{} ; {}]",
-                agent.pos_x >= settings.size_x as f64,
-                agent.pos_y >= settings.size_y as f64,
-                agent.pos_x,
-                agent.pos_y
-            );
-            agent.pos_x = agent.pos_x.max(0_f64).min(settings.size_x as f64 - 1_f64);
-            agent.pos_y = agent.pos_y.max(0_f64).min(settings.size_y as f64 - 1_f64);
-            agent.angle = rng.gen::<f64>() * 2_f64 * PI;
-            debug!("Corrected [{} ; {}]", agent.pos_x, agent.pos_y);
+        let mut wide = agent.to_f64();
+        sense_rotate_agent(trail_maps, obstacle_map, &mut wide, settings, rng);
+        *agent = Agent::from_f64(wide);
+    }
+}
+
+/// Move one agent, handling the world edges and walls
+fn move_agent(
+    agent: &mut Agent,
+    obstacle_map: &ObstacleMap,
+    settings: &Settings,
+    rng: &mut impl Rng,
+) {
+    let (old_x, old_y) = (agent.pos_x, agent.pos_y);
+    let speed = settings.species[agent.species as usize].agent_speed;
+    agent.pos_x += agent.angle.cos() * speed;
+    agent.pos_y += agent.angle.sin() * speed;
+
+    // Check Collision
+    if agent.pos_x < 0.0
+        || agent.pos_x >= settings.size_x as f64
+        || agent.pos_y < 0.0
+        || agent.pos_y >= settings.size_y as f64
+    {
+        debug!(
+            "Bonk {} {} [{} ; {}]",
+            agent.pos_x >= settings.size_x as f64,
+            agent.pos_y >= settings.size_y as f64,
+            agent.pos_x,
+            agent.pos_y
+        );
+        match settings.boundary {
+            BoundaryMode::Clamp => {
+                agent.pos_x = agent.pos_x.max(0_f64).min(settings.size_x as f64 - 1_f64);
+                agent.pos_y = agent.pos_y.max(0_f64).min(settings.size_y as f64 - 1_f64);
+                agent.angle = rng.gen::<f64>() * 2_f64 * PI;
+            }
+            BoundaryMode::Wrap => {
+                agent.pos_x = wrap_position(agent.pos_x, settings.size_x as f64);
+                agent.pos_y = wrap_position(agent.pos_y, settings.size_y as f64);
+            }
+            BoundaryMode::Reflect => {
+                if reflect_position(&mut agent.pos_x, settings.size_x as f64) {
+                    agent.angle = PI - agent.angle;
+                }
+                if reflect_position(&mut agent.pos_y, settings.size_y as f64) {
+                    agent.angle = -agent.angle;
+                }
+            }
+            BoundaryMode::Respawn => {
+                *agent = Agent {
+                    energy: agent.energy,
+                    ..spawn_agent(settings.spawn, agent.species, settings, obstacle_map, rng)
+                };
+                return;
+            }
+        }
+        debug!("Corrected [{} ; {}]", agent.pos_x, agent.pos_y);
+    }
+
+    // Check Obstacle, agents caught in a new wall are let out
+    let inside = old_x >= 0.0
+        && old_x < settings.size_x as f64
+        && old_y >= 0.0
+        && old_y < settings.size_y as f64;
+    if is_wall(obstacle_map, settings, agent.pos_x, agent.pos_y)
+        && !(inside && is_wall(obstacle_map, settings, old_x, old_y))
+    {
+        match settings.boundary {
+            BoundaryMode::Clamp | BoundaryMode::Wrap => {
+                agent.angle = rng.gen::<f64>() * 2_f64 * PI;
+            }
+            BoundaryMode::Reflect => {
+                let blocked_x = inside && is_wall(obstacle_map, settings, agent.pos_x, old_y);
+                let blocked_y = inside && is_wall(obstacle_map, settings, old_x, agent.pos_y);
+                if blocked_x {
+                    agent.angle = PI - agent.angle;
+                }
+                if blocked_y {
+                    agent.angle = -agent.angle;
+                }
+                if !blocked_x && !blocked_y {
+                    agent.angle += PI;
+                }
+            }
+            BoundaryMode::Respawn => {
+                *agent = Agent {
+                    energy: agent.energy,
+                    ..spawn_agent(settings.spawn, agent.species, settings, obstacle_map, rng)
+                };
+                return;
+            }
+        }
+        if inside {
+            agent.pos_x = old_x;
+            agent.pos_y = old_y;
+        }
+    }
+}
+
+/// Step 3: Move
+pub fn cpu_move<R: Real>(
+    agents: &mut Agents<R>,
+    obstacle_map: &ObstacleMap,
+    occupancy: &mut OccupancyMap,
+    settings: &Settings,
+    rng: &mut impl Rng,
+) {
+    let agents = &mut agents[0..settings.agent_n as usize];
+    if settings.movement == MovementMode::Exclusive {
+        exclusive_move(agents, obstacle_map, occupancy, settings, rng);
+        return;
+    }
+    for agent in agents {
+        let mut wide = agent.to_f64();
+        move_agent(&mut wide, obstacle_map, settings, rng);
+        *agent = Agent::from_f64(wide);
+    }
+}
+
+/// Move agents one at a time in a random order, into free cells only, the
+/// blocked ones stay and turn to a random heading. `occupancy` is scratch
+/// space reused across steps
+fn exclusive_move<R: Real>(
+    agents: &mut [Agent<R>],
+    obstacle_map: &ObstacleMap,
+    occupancy: &mut OccupancyMap,
+    settings: &Settings,
+    rng: &mut impl Rng,
+) {
+    count_occupancy(agents, occupancy, settings);
+    let mut order: Vec<usize> = (0..agents.len()).collect();
+    order.shuffle(rng);
+    for i in order {
+        let agent = &mut agents[i];
+        let old = *agent;
+        let mut wide = old.to_f64();
+        move_agent(&mut wide, obstacle_map, settings, rng);
+        wide.blocked = false;
+        // Cells of the stored positions, rounding can move them across one
+        *agent = Agent::from_f64(wide);
+        let (from, to) = (agent_cell(&old, settings), agent_cell(agent, settings));
+        if to == from {
+            continue;
+        }
+        if to.is_some_and(|to| occupancy[to] > 0) {
+            *agent = Agent {
+                angle: R::from_f64(rng.gen::<f64>() * 2_f64 * PI),
+                blocked: true,
+                ..old
+            };
+            continue;
+        }
+        // Respawned agents leave their old cell too
+        if let Some(from) = from {
+            occupancy[from] -= 1;
         }
+        if let Some(to) = to {
+            occupancy[to] += 1;
+        }
+    }
+}
+
+/// Count the agents of each cell into `occupancy`, sized to the world
+fn count_occupancy<R: Real>(
+    agents: &[Agent<R>],
+    occupancy: &mut OccupancyMap,
+    settings: &Settings,
+) {
+    occupancy.clear();
+    occupancy.resize((settings.size_x * settings.size_y) as usize, 0);
+    for cell in agents
+        .iter()
+        .filter_map(|agent| agent_cell(agent, settings))
+    {
+        occupancy[cell] += 1;
     }
 }
 
-/// Step 4: Deposit
-pub fn cpu_deposit(agents: &Agents, trail_map: &mut TrailMap, settings: &Settings) {
-    for agent in &agents[0..settings.agent_n as usize] {
-        let x = agent.pos_x.floor() as usize;
-        let y = agent.pos_y.floor() as usize;
-        trail_map[x + MAX_SIZE_X as usize * y] = settings.trail_weight;
+/// Cell of an agent when it is in the world, outside walls and holds no
+/// other agent
+fn free_cell<R: Real>(
+    agent: &Agent<R>,
+    obstacle_map: &ObstacleMap,
+    occupancy: &OccupancyMap,
+    settings: &Settings,
+) -> Option<usize> {
+    agent_cell(agent, settings).filter(|&cell| !obstacle_map[cell] && occupancy[cell] == 0)
+}
+
+/// Give each live agent from `placed` on a cell of its own for exclusive
+/// movement, the ones before already having one. Agents sharing a cell are
+/// respawned as configured until they land in a free one, those finding none
+/// are dropped as dead ones are. Returns the new number of agents
+pub fn exclusive_place<R: Real>(
+    agents: &mut Agents<R>,
+    placed: usize,
+    obstacle_map: &ObstacleMap,
+    occupancy: &mut OccupancyMap,
+    settings: &Settings,
+    rng: &mut impl Rng,
+) -> u32 {
+    let mut agent_n = settings.agent_n as usize;
+    count_occupancy(&agents[0..placed.min(agent_n)], occupancy, settings);
+    let mut i = placed;
+    while i < agent_n {
+        let mut agent = agents[i];
+        let mut tries = 0;
+        let cell = loop {
+            if let Some(cell) = free_cell(&agent, obstacle_map, occupancy, settings) {
+                break Some(cell);
+            }
+            if tries == SPAWN_TRIES {
+                break None;
+            }
+            tries += 1;
+            agent = Agent {
+                energy: agent.energy,
+                ..Agent::from_f64(Agent::spawn(settings.spawn, agent.species, settings, rng))
+            };
+        };
+        if let Some(cell) = cell {
+            occupancy[cell] += 1;
+            agents[i] = agent;
+            i += 1;
+        } else {
+            // The last live agent is placed in this slot next
+            agent_n -= 1;
+            agents.swap(i, agent_n);
+        }
+    }
+    agent_n as u32
+}
+
+/// Move `agent` to a free one of the eight cells around it, picked at
+/// random, and return that cell
+fn free_neighbour<R: Real>(
+    agent: &mut Agent<R>,
+    obstacle_map: &ObstacleMap,
+    occupancy: &OccupancyMap,
+    settings: &Settings,
+    rng: &mut impl Rng,
+) -> Option<usize> {
+    const NEIGHBOURS: [(f64, f64); 8] = [
+        (-1.0, -1.0),
+        (0.0, -1.0),
+        (1.0, -1.0),
+        (-1.0, 0.0),
+        (1.0, 0.0),
+        (-1.0, 1.0),
+        (0.0, 1.0),
+        (1.0, 1.0),
+    ];
+    let start = rng.gen_range(0..NEIGHBOURS.len());
+    let wide = agent.to_f64();
+    NEIGHBOURS
+        .iter()
+        .cycle()
+        .skip(start)
+        .take(NEIGHBOURS.len())
+        .find_map(|(dx, dy)| {
+            let moved = Agent::from_f64(Agent {
+                pos_x: wide.pos_x + dx,
+                pos_y: wide.pos_y + dy,
+                ..wide
+            });
+            let cell = free_cell(&moved, obstacle_map, occupancy, settings)?;
+            *agent = moved;
+            Some(cell)
+        })
+}
+
+/// Cell holding an agent, `None` outside the world
+fn agent_cell<R: Real>(agent: &Agent<R>, settings: &Settings) -> Option<usize> {
+    let (x, y) = agent.position();
+    let inside = x >= 0.0 && x < settings.size_x as f64 && y >= 0.0 && y < settings.size_y as f64;
+    inside.then(|| x.floor() as usize + settings.size_x as usize * y.floor() as usize)
+}
+
+/// Step 4: Deposit, each agent in its species trail channel. `coverage` is
+/// scratch space reused across steps, sized to the world when needed
+pub fn cpu_deposit<R: Real>(
+    agents: &Agents<R>,
+    trail_maps: &mut [TrailMap<R>],
+    coverage: &mut CoverageMap,
+    settings: &Settings,
+) {
+    let agents = &agents[0..settings.agent_n as usize];
+    fit_coverage(coverage, settings);
+    for (species, trail_map) in trail_maps.iter_mut().enumerate() {
+        deposit_channel(agents, species, trail_map, coverage, settings);
+    }
+}
+
+/// Size `coverage` to the world, a new map starts at zero
+fn fit_coverage(coverage: &mut CoverageMap, settings: &Settings) {
+    let cells = (settings.size_x * settings.size_y) as usize;
+    if coverage.len() != cells {
+        *coverage = (0..cells).map(|_| AtomicU32::new(0)).collect();
+    }
+}
+
+/// Deposit of the agents of `species` in their trail channel
+fn deposit_channel<R: Real>(
+    agents: &[Agent<R>],
+    species: usize,
+    trail_map: &mut TrailMap<R>,
+    coverage: &mut CoverageMap,
+    settings: &Settings,
+) {
+    let trail_weight = settings.species[species].trail_weight;
+    let agents = agents
+        .iter()
+        .filter_map(|agent| Some((agent, deposit_cell(agent, species, settings)?)));
+    if !settings.bilinear {
+        for (_, cell) in agents {
+            let cell = &mut trail_map[cell];
+            *cell = R::from_f64(deposit(cell.to_f64(), trail_weight, 1.0, settings));
+        }
+        return;
+    }
+    // Shares of all agents first, the deposit mode applies to their sum
+    for (agent, cell) in agents {
+        cover(agent, cell, settings, |cell, share| {
+            *coverage[cell].get_mut() += share;
+        });
+    }
+    for (value, share) in trail_map.iter_mut().zip(coverage.iter_mut()) {
+        let share = std::mem::take(share.get_mut());
+        *value = R::from_f64(deposit_share(value.to_f64(), trail_weight, share, settings));
     }
 }
 
-/// Step 5&6: Diffuse & Decay
-pub fn cpu_diffuse_decay(trail_map: &mut TrailMap, settings: &Settings) {
-    let source = trail_map.clone();
+/// Cell of an agent leaving trail in the channel of `species`, agents
+/// blocked by an exclusive move or outside the world leave none, as on the
+/// device
+fn deposit_cell<R: Real>(agent: &Agent<R>, species: usize, settings: &Settings) -> Option<usize> {
+    let blocked = settings.movement == MovementMode::Exclusive && agent.blocked;
+    if agent.species as usize != species || blocked {
+        return None;
+    }
+    agent_cell(agent, settings)
+}
+
+/// Pass to `add` the cells covered by an agent in `cell` and its share of
+/// each in `SUBCELL_STEPS²` units
+fn cover<R: Real>(
+    agent: &Agent<R>,
+    cell: usize,
+    settings: &Settings,
+    mut add: impl FnMut(usize, u32),
+) {
+    if !settings.bilinear {
+        add(cell, SUBCELL_STEPS * SUBCELL_STEPS);
+        return;
+    }
+    let (x, y) = agent.position();
+    for (x, y, share) in splat(x, y) {
+        if let Some(cell) = boundary_cell(x, y, settings) {
+            add(cell, share);
+        }
+    }
+}
+
+/// Cells overlapped by the one cell square centred on an agent, and the
+/// share of the square in each in `SUBCELL_STEPS²` units
+fn splat(x: f64, y: f64) -> [(isize, isize, u32); 4] {
+    let (x, y) = (x - 0.5, y - 0.5);
+    let (cell_x, cell_y) = (x.floor(), y.floor());
+    let share_x = ((x - cell_x) * SUBCELL_STEPS as f64).round() as u32;
+    let share_y = ((y - cell_y) * SUBCELL_STEPS as f64).round() as u32;
+    let (cell_x, cell_y) = (cell_x as isize, cell_y as isize);
+    [
+        (
+            cell_x,
+            cell_y,
+            (SUBCELL_STEPS - share_x) * (SUBCELL_STEPS - share_y),
+        ),
+        (cell_x + 1, cell_y, share_x * (SUBCELL_STEPS - share_y)),
+        (cell_x, cell_y + 1, (SUBCELL_STEPS - share_x) * share_y),
+        (cell_x + 1, cell_y + 1, share_x * share_y),
+    ]
+}
+
+/// Trail of a cell after agents covering `share` in `SUBCELL_STEPS²` units
+/// deposit `weight` in it
+fn deposit_share(value: f64, weight: f64, share: u32, settings: &Settings) -> f64 {
+    if share == 0 {
+        return value;
+    }
+    let coverage = share as f64 / (SUBCELL_STEPS * SUBCELL_STEPS) as f64;
+    deposit(value, weight, coverage, settings)
+}
+
+/// Trail of a cell after agents covering `coverage` cells in total deposit
+/// `weight` in it, a whole agent covers one
+fn deposit(value: f64, weight: f64, coverage: f64, settings: &Settings) -> f64 {
+    match settings.deposit {
+        DepositMode::Overwrite if coverage >= 1.0 => weight,
+        DepositMode::Overwrite => value + (weight - value) * coverage,
+        DepositMode::Additive => value + weight * coverage,
+        DepositMode::Capped => (value + weight * coverage).min(settings.deposit_cap),
+        DepositMode::Max => value.max(weight * coverage.min(1.0)),
+    }
+}
+
+/// Step 4b: Emit nutrients in every trail channel, except inside walls
+pub fn cpu_inject_nutrients<R: Real>(
+    nutrient_map: &NutrientMap,
+    obstacle_map: &ObstacleMap,
+    trail_maps: &mut [TrailMap<R>],
+    settings: &Settings,
+) {
     for y in 0..settings.size_y {
         for x in 0..settings.size_x {
-            // Diffuse
-            let mut sum = 0.0;
-            for offset_x in [-1, 0, 1] {
-                for offset_y in [-1, 0, 1] {
-                    let pick_x =
-                        min(max(x as isize + offset_x, 0), MAX_SIZE_X as isize - 1) as usize;
-                    let pick_y =
-                        min(max(y as isize + offset_y, 0), MAX_SIZE_Y as isize - 1) as usize;
-                    sum += source[pick_x + MAX_SIZE_X as usize * pick_y];
+            let cell = (x + settings.size_x * y) as usize;
+            if nutrient_map[cell] > 0.0 && !obstacle_map[cell] {
+                for trail_map in trail_maps.iter_mut() {
+                    trail_map[cell] = R::from_f64(trail_map[cell].to_f64() + nutrient_map[cell]);
                 }
             }
-            sum /= 9.0;
-            trail_map[(x + MAX_SIZE_X * y) as usize] *= 1.0 - settings.trail_diffuse;
-            trail_map[(x + MAX_SIZE_X * y) as usize] += sum * settings.trail_diffuse;
+        }
+    }
+}
 
-            // Decay
-            trail_map[(x + MAX_SIZE_X * y) as usize] =
-                0_f64.max(trail_map[(x + MAX_SIZE_X * y) as usize] - settings.trail_decay);
+/// Step 5&6: Diffuse & Decay from `source` into `target` with the settings
+/// kernel, walls stay empty and are left out of the stencil
+pub fn cpu_diffuse_decay<R: Real>(
+    source: &TrailMap<R>,
+    target: &mut TrailMap<R>,
+    obstacle_map: &ObstacleMap,
+    settings: &Settings,
+) {
+    let diffusion = Diffusion::new(settings);
+    for y in 0..settings.size_y {
+        for x in 0..settings.size_x {
+            target[(x + settings.size_x * y) as usize] = R::from_f64(diffuse_decay_cell(
+                source,
+                obstacle_map,
+                &diffusion,
+                x,
+                y,
+                settings,
+            ));
         }
     }
 }
+
+/// Diffusion and decay parameters derived once per step from the settings
+struct Diffusion {
+    stencil: Stencil,
+    /// Sum of the stencil weights
+    total: f64,
+    /// Decay factor and amount subtracted after it
+    decay: (f64, f64),
+}
+
+impl Diffusion {
+    fn new(settings: &Settings) -> Diffusion {
+        let stencil = settings.diffusion.stencil();
+        Diffusion {
+            total: stencil.weights.iter().sum(),
+            stencil,
+            decay: settings.decay(),
+        }
+    }
+}
+
+/// New value of one cell after diffusion and decay, walls are emptied and
+/// their weight left out of the stencil
+fn diffuse_decay_cell<R: Real>(
+    source: &TrailMap<R>,
+    obstacle_map: &ObstacleMap,
+    diffusion: &Diffusion,
+    x: u32,
+    y: u32,
+    settings: &Settings,
+) -> f64 {
+    if obstacle_map[(x + settings.size_x * y) as usize] {
+        return 0.0;
+    }
+    // Diffuse
+    let stencil = &diffusion.stencil;
+    let radius = stencil.radius as isize;
+    let mut sum = 0.0;
+    let mut weight = diffusion.total;
+    for offset_x in -radius..=radius {
+        for offset_y in -radius..=radius {
+            let stencil_weight = stencil.weight(offset_x, offset_y);
+            match boundary_cell(x as isize + offset_x, y as isize + offset_y, settings) {
+                Some(cell) if obstacle_map[cell] => weight -= stencil_weight,
+                Some(cell) => sum += stencil_weight * source[cell].to_f64(),
+                None => {}
+            }
+        }
+    }
+    if weight != 0.0 {
+        sum /= weight;
+    }
+    let mut value =
+        source[(x + settings.size_x * y) as usize].to_f64() * (1.0 - settings.trail_diffuse);
+    value += sum * settings.trail_diffuse;
+
+    // Decay
+    let (factor, amount) = diffusion.decay;
+    0_f64.max(value * factor - amount)
+}
+
+/// Step 7: Lifecycle, agents feed on the nutrients and their species trail
+/// in their cell and pay for moving, the starving ones are replaced by the
+/// last living agent and the rich ones divide, children being appended after
+/// the living agents up to `max_agents`. With exclusive movement children
+/// take a free cell around their parent, which does not divide without one.
+/// Sequential with every backend so births and deaths keep one order,
+/// returns the new number of agents
+pub fn cpu_lifecycle<R: Real>(
+    agents: &mut Agents<R>,
+    trail_maps: &[TrailMap<R>],
+    nutrient_map: &NutrientMap,
+    obstacle_map: &ObstacleMap,
+    occupancy: &mut OccupancyMap,
+    settings: &Settings,
+    rng: &mut impl Rng,
+) -> u32 {
+    let mut agent_n = settings.agent_n as usize;
+
+    // Feed & starve
+    let mut i = 0;
+    while i < agent_n {
+        let agent = &mut agents[i];
+        // Agents outside the world find nothing to feed on
+        let food = agent_cell(agent, settings).map_or(0.0, |cell| {
+            settings.food_gain * nutrient_map[cell]
+                + settings.trail_gain * trail_maps[agent.species as usize][cell].to_f64()
+        });
+        agent.energy +=
+            food - settings.move_cost * settings.species[agent.species as usize].agent_speed;
+        if agent.energy < settings.death_energy {
+            // The last living agent is fed in this slot next
+            agent_n -= 1;
+            agents.swap(i, agent_n);
+        } else {
+            i += 1;
+        }
+    }
+
+    // Divide, children only live from the next step
+    let max_agents = (settings.max_agents as usize).min(agents.len());
+    let exclusive = settings.movement == MovementMode::Exclusive;
+    if exclusive {
+        count_occupancy(&agents[0..agent_n], occupancy, settings);
+    }
+    let living = agent_n;
+    for i in 0..living {
+        if agent_n >= max_agents {
+            break;
+        }
+        if agents[i].energy >= settings.division_energy {
+            let mut child = Agent {
+                angle: R::from_f64(rng.gen::<f64>() * 2_f64 * PI),
+                ..agents[i]
+            };
+            if exclusive {
+                let Some(cell) = free_neighbour(&mut child, obstacle_map, occupancy, settings, rng)
+                else {
+                    continue;
+                };
+                occupancy[cell] += 1;
+            }
+            agents[i].energy /= 2.0;
+            child.energy = agents[i].energy;
+            agents[agent_n] = child;
+            agent_n += 1;
+        }
+    }
+    agent_n as u32
+}
+
+/// Step 1&2: Sense & Rotate on all threads, each chunk of agents has its own
+/// random generator seeded from `seed`
+pub fn par_sense_rotate<R: Real>(
+    trail_maps: &[TrailMap<R>],
+    obstacle_map: &ObstacleMap,
+    agents: &mut Agents<R>,
+    settings: &Settings,
+    seed: u64,
+) {
+    agents[0..settings.agent_n as usize]
+        .par_chunks_mut(PAR_CHUNK)
+        .enumerate()
+        .for_each(|(chunk, agents)| {
+            let mut rng = ChaCha8Rng::seed_from_u64(seed.wrapping_add(chunk as u64));
+            for agent in agents {
+                let mut wide = agent.to_f64();
+                sense_rotate_agent(trail_maps, obstacle_map, &mut wide, settings, &mut rng);
+                *agent = Agent::from_f64(wide);
+            }
+        });
+}
+
+/// Step 3: Move on all threads, each chunk of agents has its own random
+/// generator seeded from `seed`. Exclusive moves depend on the ones before
+/// them and stay on one thread
+pub fn par_move<R: Real>(
+    agents: &mut Agents<R>,
+    obstacle_map: &ObstacleMap,
+    occupancy: &mut OccupancyMap,
+    settings: &Settings,
+    seed: u64,
+) {
+    if settings.movement == MovementMode::Exclusive {
+        cpu_move(
+            agents,
+            obstacle_map,
+            occupancy,
+            settings,
+            &mut ChaCha8Rng::seed_from_u64(seed),
+        );
+        return;
+    }
+    agents[0..settings.agent_n as usize]
+        .par_chunks_mut(PAR_CHUNK)
+        .enumerate()
+        .for_each(|(chunk, agents)| {
+            let mut rng = ChaCha8Rng::seed_from_u64(seed.wrapping_add(chunk as u64));
+            for agent in agents {
+                let mut wide = agent.to_f64();
+                move_agent(&mut wide, obstacle_map, settings, &mut rng);
+                *agent = Agent::from_f64(wide);
+            }
+        });
+}
+
+/// Step 4: Deposit on all threads, one channel after the other. Agents add
+/// their shares to an atomic coverage map as on the device, whole numbers
+/// whose sum doesn't depend on the order, then each row applies the deposit
+/// mode to its cells
+pub fn par_deposit<R: Real>(
+    agents: &Agents<R>,
+    trail_maps: &mut [TrailMap<R>],
+    coverage: &mut CoverageMap,
+    settings: &Settings,
+) {
+    let agents = &agents[0..settings.agent_n as usize];
+    let size_x = settings.size_x as usize;
+    fit_coverage(coverage, settings);
+    let coverage = &*coverage;
+    for (species, trail_map) in trail_maps.iter_mut().enumerate() {
+        let trail_weight = settings.species[species].trail_weight;
+        agents.par_iter().for_each(|agent| {
+            if let Some(cell) = deposit_cell(agent, species, settings) {
+                cover(agent, cell, settings, |cell, share| {
+                    coverage[cell].fetch_add(share, Ordering::Relaxed);
+                });
+            }
+        });
+        trail_map
+            .par_chunks_mut(size_x)
+            .zip(coverage.par_chunks(size_x))
+            .for_each(|(row, shares)| {
+                for (value, share) in row.iter_mut().zip(shares) {
+                    let share = share.swap(0, Ordering::Relaxed);
+                    *value =
+                        R::from_f64(deposit_share(value.to_f64(), trail_weight, share, settings));
+                }
+            });
+    }
+}
+
+/// Step 5&6: Diffuse & Decay from `source` into `target` with one row per task
+pub fn par_diffuse_decay<R: Real>(
+    source: &TrailMap<R>,
+    target: &mut TrailMap<R>,
+    obstacle_map: &ObstacleMap,
+    settings: &Settings,
+) {
+    let diffusion = Diffusion::new(settings);
+    target
+        .par_chunks_mut(settings.size_x as usize)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for x in 0..settings.size_x {
+                let value =
+                    diffuse_decay_cell(source, obstacle_map, &diffusion, x, y as u32, settings);
+                row[x as usize] = R::from_f64(value);
+            }
+        });
+}
+
+/// Time spent in each phase of a step, decay runs in the same pass as
+/// diffusion
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StepTimings {
+    pub sense_rotate: Duration,
+    pub movement: Duration,
+    pub deposit: Duration,
+    pub nutrients: Duration,
+    pub diffuse_decay: Duration,
+    pub lifecycle: Duration,
+}
+
+impl StepTimings {
+    /// Phase names and times, in step order
+    pub fn phases(&self) -> [(&'static str, Duration); 6] {
+        [
+            ("sense_rotate", self.sense_rotate),
+            ("move", self.movement),
+            ("deposit", self.deposit),
+            ("nutrients", self.nutrients),
+            ("diffuse_decay", self.diffuse_decay),
+            ("lifecycle", self.lifecycle),
+        ]
+    }
+
+    pub fn total(&self) -> Duration {
+        self.phases().iter().map(|(_, time)| *time).sum()
+    }
+}
+
+impl AddAssign for StepTimings {
+    fn add_assign(&mut self, other: StepTimings) {
+        self.sense_rotate += other.sense_rotate;
+        self.movement += other.movement;
+        self.deposit += other.deposit;
+        self.nutrients += other.nutrients;
+        self.diffuse_decay += other.diffuse_decay;
+        self.lifecycle += other.lifecycle;
+    }
+}
+
+impl Mul<u32> for StepTimings {
+    type Output = StepTimings;
+
+    fn mul(self, n: u32) -> StepTimings {
+        StepTimings {
+            sense_rotate: self.sense_rotate * n,
+            movement: self.movement * n,
+            deposit: self.deposit * n,
+            nutrients: self.nutrients * n,
+            diffuse_decay: self.diffuse_decay * n,
+            lifecycle: self.lifecycle * n,
+        }
+    }
+}
+
+impl Div<u32> for StepTimings {
+    type Output = StepTimings;
+
+    fn div(self, n: u32) -> StepTimings {
+        StepTimings {
+            sense_rotate: self.sense_rotate / n,
+            movement: self.movement / n,
+            deposit: self.deposit / n,
+            nutrients: self.nutrients / n,
+            diffuse_decay: self.diffuse_decay / n,
+            lifecycle: self.lifecycle / n,
+        }
+    }
+}
+
+/// Run one phase and return how long it took
+fn timed(phase: impl FnOnce()) -> Duration {
+    let start = Instant::now();
+    phase();
+    start.elapsed()
+}
+
+/// Agents and trail channels in one float type
+#[derive(Clone, Default)]
+struct State<R: Real> {
+    agents: Agents<R>,
+    trail_maps: Vec<TrailMap<R>>,
+    /// Second buffer of each channel, diffusion writes there and swaps
+    trail_backs: Vec<TrailMap<R>>,
+}
+
+impl<R: Real> State<R> {
+    fn precision(&self) -> Precision {
+        R::PRECISION
+    }
+
+    /// Same state rounded to the float type `S`
+    fn convert<S: Real>(&self) -> State<S> {
+        let convert = |trail_map: &TrailMap<R>| {
+            trail_map
+                .iter()
+                .map(|value| S::from_f64(value.to_f64()))
+                .collect()
+        };
+        State {
+            agents: self
+                .agents
+                .iter()
+                .map(|agent| Agent::from_f64(agent.to_f64()))
+                .collect(),
+            trail_maps: self.trail_maps.iter().map(convert).collect(),
+            trail_backs: self.trail_backs.iter().map(convert).collect(),
+        }
+    }
+}
+
+/// Host state in the simulation precision
+enum Host {
+    Single(State<f32>),
+    Double(State<f64>),
+}
+
+/// Run `$body` with `$state` bound to the host state of `$host`, whatever
+/// its precision
+macro_rules! with_state {
+    ($host:expr, $state:ident => $body:expr) => {
+        match $host {
+            Host::Single($state) => $body,
+            Host::Double($state) => $body,
+        }
+    };
+}
+
+/// Simulation state: agents, one trail map per species, nutrients and the
+/// settings driving them
+pub struct Simulation {
+    settings: Settings,
+    /// Dimensions the maps are allocated for, followed by the settings ones
+    /// before the next operation on them
+    size_x: u32,
+    size_y: u32,
+    /// Agents and trail channels, in the float type of every backend
+    host: Host,
+    /// Scratch space of the deposit, kept between steps
+    coverage: CoverageMap,
+    /// Scratch space of exclusive moves and placements, kept between steps
+    occupancy: OccupancyMap,
+    /// Live agents known to hold a cell each for exclusive movement, the
+    /// ones after them are placed before the next step
+    placed_n: u32,
+    nutrient_map: NutrientMap,
+    obstacle_map: ObstacleMap,
+    /// ChaCha8 keeps its stream across rand releases, unlike `StdRng`, so
+    /// saved seeds keep replaying the same run
+    rng: ChaCha8Rng,
+    step_count: u64,
+    /// Phase times of the last step, averaged over the last OpenCL batch
+    timings: StepTimings,
+    /// OpenCL programs and buffers, created on the first GPU step
+    #[cfg(feature = "opencl")]
+    gpu: Option<GpuBackend>,
+    /// Whether the host state changed since the last upload to the device
+    #[cfg(feature = "opencl")]
+    gpu_stale: bool,
+    /// Whether the device agents moved on since they were last read, they
+    /// only come back to the host when needed
+    #[cfg(feature = "opencl")]
+    agents_stale: bool,
+    /// Device picked for OpenCL, the first one found when `None`
+    #[cfg(feature = "opencl")]
+    gpu_device: Option<GpuDevice>,
+    /// Whether OpenCL steps time each phase, waiting for the device after it
+    #[cfg(feature = "opencl")]
+    gpu_timed: bool,
+}
+
+impl Simulation {
+    /// Simulation of `settings`, brought back in the supported range first
+    pub fn new(mut settings: Settings) -> Self {
+        settings.clamp_to_max();
+        let (size_x, size_y) = (settings.size_x, settings.size_y);
+        let cells = (size_x * size_y) as usize;
+        let mut simulation = Simulation {
+            rng: ChaCha8Rng::seed_from_u64(settings.seed),
+            settings,
+            size_x,
+            size_y,
+            host: Host::Double(State::default()),
+            coverage: Vec::new(),
+            occupancy: Vec::new(),
+            placed_n: 0,
+            nutrient_map: vec![0.0; cells],
+            obstacle_map: vec![false; cells],
+            step_count: 0,
+            timings: StepTimings::default(),
+            #[cfg(feature = "opencl")]
+            gpu: None,
+            #[cfg(feature = "opencl")]
+            gpu_stale: true,
+            #[cfg(feature = "opencl")]
+            agents_stale: false,
+            #[cfg(feature = "opencl")]
+            gpu_device: None,
+            #[cfg(feature = "opencl")]
+            gpu_timed: false,
+        };
+        simulation.reset();
+        simulation
+    }
+
+    /// Run all steps once on the CPU
+    pub fn step(&mut self) {
+        self.step_cpu(false);
+    }
+
+    /// Run all steps once on all CPU threads, statistically equivalent to
+    /// `step` but not bitwise
+    pub fn step_parallel(&mut self) {
+        self.step_cpu(true);
+    }
+
+    /// One step on the CPU, each phase running its parallel version when
+    /// `parallel` is set and its serial one otherwise
+    fn step_cpu(&mut self, parallel: bool) {
+        self.sync_agents();
+        self.sync_size();
+        self.sync_species();
+        self.sync_exclusive();
+
+        self.timings = with_state!(&mut self.host, state => {
+            let sense_rotate = timed(|| {
+                let (trail_maps, obstacle_map) = (&state.trail_maps, &self.obstacle_map);
+                if parallel {
+                    let seed = self.rng.gen();
+                    par_sense_rotate(
+                        trail_maps,
+                        obstacle_map,
+                        &mut state.agents,
+                        &self.settings,
+                        seed,
+                    )
+                } else {
+                    cpu_sense_rotate(
+                        trail_maps,
+                        obstacle_map,
+                        &mut state.agents,
+                        &self.settings,
+                        &mut self.rng,
+                    )
+                }
+            });
+
+            let movement = timed(|| {
+                let (agents, occupancy) = (&mut state.agents, &mut self.occupancy);
+                if parallel {
+                    let seed = self.rng.gen();
+                    par_move(agents, &self.obstacle_map, occupancy, &self.settings, seed)
+                } else {
+                    cpu_move(
+                        agents,
+                        &self.obstacle_map,
+                        occupancy,
+                        &self.settings,
+                        &mut self.rng,
+                    )
+                }
+            });
+
+            let deposit = timed(|| {
+                let deposit = if parallel { par_deposit } else { cpu_deposit };
+                deposit(
+                    &state.agents,
+                    &mut state.trail_maps,
+                    &mut self.coverage,
+                    &self.settings,
+                )
+            });
+
+            let nutrients = timed(|| {
+                cpu_inject_nutrients(
+                    &self.nutrient_map,
+                    &self.obstacle_map,
+                    &mut state.trail_maps,
+                    &self.settings,
+                )
+            });
+
+            let diffuse_decay = timed(|| {
+                let diffuse_decay = if parallel {
+                    par_diffuse_decay
+                } else {
+                    cpu_diffuse_decay
+                };
+                for (trail_map, trail_back) in
+                    state.trail_maps.iter_mut().zip(&mut state.trail_backs)
+                {
+                    diffuse_decay(trail_map, trail_back, &self.obstacle_map, &self.settings);
+                    std::mem::swap(trail_map, trail_back);
+                }
+            });
+
+            let lifecycle = timed(|| {
+                if self.settings.lifecycle {
+                    self.settings.agent_n = cpu_lifecycle(
+                        &mut state.agents,
+                        &state.trail_maps,
+                        &self.nutrient_map,
+                        &self.obstacle_map,
+                        &mut self.occupancy,
+                        &self.settings,
+                        &mut self.rng,
+                    );
+                }
+            });
+
+            StepTimings {
+                sense_rotate,
+                movement,
+                deposit,
+                nutrients,
+                diffuse_decay,
+                lifecycle,
+            }
+        });
+        self.lifecycle_placed();
+        self.step_count += 1;
+        self.host_changed();
+    }
+
+    /// Run all steps once with OpenCL
+    #[cfg(feature = "opencl")]
+    pub fn step_gpu(&mut self) -> Result<(), GpuError> {
+        self.step_gpu_n(1)
+    }
+
+    /// Run all steps `n` times with OpenCL, agents and trails stay on the
+    /// device, trails are read back once at the end and agents only when
+    /// asked for, the host state is uploaded again only after it was changed.
+    /// On error the trails are the ones before the call and the agents the
+    /// last ones the device holds, so stepping can go on on the CPU. With the
+    /// lifecycle on, agents and trails come back after every step to run it
+    /// on the host, and an error can leave the host a few steps ahead
+    #[cfg(feature = "opencl")]
+    pub fn step_gpu_n(&mut self, n: usize) -> Result<(), GpuError> {
+        self.sync_size();
+        self.sync_species();
+        self.sync_exclusive();
+        let result = self.run_gpu(n);
+        if result.is_err() {
+            self.sync_agents();
+            self.gpu_stale = true;
+        }
+        result
+    }
+
+    #[cfg(feature = "opencl")]
+    fn run_gpu(&mut self, n: usize) -> Result<(), GpuError> {
+        if self.gpu_stale {
+            // The upload below sends the agents back
+            self.sync_agents();
+        }
+        let gpu = match &mut self.gpu {
+            Some(gpu) => gpu,
+            gpu @ None => {
+                self.gpu_stale = true;
+                let precision = with_state!(&self.host, state => state.precision());
+                gpu.insert(GpuBackend::new(
+                    self.gpu_device.as_ref(),
+                    precision,
+                    &self.settings,
+                )?)
+            }
+        };
+        if self.gpu_stale {
+            with_state!(&self.host, state => gpu.upload(
+                &state.agents,
+                &state.trail_maps,
+                &self.nutrient_map,
+                &self.obstacle_map,
+                self.rng.gen(),
+            ))?;
+            self.gpu_stale = false;
+        }
+
+        let mut timings = StepTimings::default();
+        for _ in 0..n {
+            timings += gpu.step(&self.settings, self.gpu_timed)?;
+            self.agents_stale = true;
+            if self.settings.lifecycle {
+                with_state!(&mut self.host, state => {
+                    gpu.read_agents(&mut state.agents, &self.settings)?;
+                    self.agents_stale = false;
+                    gpu.read_trails(&mut state.trail_maps)?;
+                    timings.lifecycle += timed(|| {
+                        self.settings.agent_n = cpu_lifecycle(
+                            &mut state.agents,
+                            &state.trail_maps,
+                            &self.nutrient_map,
+                            &self.obstacle_map,
+                            &mut self.occupancy,
+                            &self.settings,
+                            &mut self.rng,
+                        )
+                    });
+                    if self.settings.movement == MovementMode::Exclusive {
+                        self.placed_n = self.settings.agent_n;
+                    }
+                    gpu.upload_agents(&state.agents, &self.settings)?;
+                });
+            }
+        }
+        self.timings = timings / n.max(1) as u32;
+
+        with_state!(&mut self.host, state => gpu.read_trails(&mut state.trail_maps))?;
+        self.step_count += n as u64;
+        Ok(())
+    }
+
+    /// Run the next OpenCL steps on `device`, kernels are built again on it
+    #[cfg(feature = "opencl")]
+    pub fn set_gpu_device(&mut self, device: Option<GpuDevice>) {
+        self.sync_agents();
+        self.gpu_device = device;
+        self.gpu = None;
+    }
+
+    #[cfg(feature = "opencl")]
+    pub fn gpu_device(&self) -> Option<&GpuDevice> {
+        self.gpu_device.as_ref()
+    }
+
+    /// Time each phase of the next OpenCL steps, which holds the device back
+    /// between phases, otherwise their phase times stay at zero
+    #[cfg(feature = "opencl")]
+    pub fn set_gpu_timed(&mut self, timed: bool) {
+        self.gpu_timed = timed;
+    }
+
+    /// Float type agents and trail channels are kept in from now on, with
+    /// every backend: the host state is rounded to it and the OpenCL kernels
+    /// are built again for it
+    pub fn set_precision(&mut self, precision: Precision) {
+        if precision == self.precision() {
+            return;
+        }
+        self.sync_size();
+        self.sync_agents();
+        self.host = match precision {
+            Precision::Single => Host::Single(with_state!(&self.host, state => state.convert())),
+            Precision::Double => Host::Double(with_state!(&self.host, state => state.convert())),
+        };
+        #[cfg(feature = "opencl")]
+        {
+            self.gpu = None;
+        }
+        // Rounding can move agents into a neighbour cell
+        self.placed_n = 0;
+        self.sync_exclusive();
+        self.host_changed();
+    }
+
+    pub fn precision(&self) -> Precision {
+        with_state!(&self.host, state => state.precision())
+    }
+
+    /// Float type the OpenCL kernels were built for, single precision on
+    /// devices without double support whatever was asked, `None` until the
+    /// first OpenCL step
+    #[cfg(feature = "opencl")]
+    pub fn gpu_precision(&self) -> Option<Precision> {
+        self.gpu.as_ref().map(GpuBackend::precision)
+    }
+
+    /// Follow the lifecycle under exclusive movement: survivors and children
+    /// all hold a cell of their own
+    fn lifecycle_placed(&mut self) {
+        if self.settings.lifecycle && self.settings.movement == MovementMode::Exclusive {
+            self.placed_n = self.settings.agent_n;
+        }
+    }
+
+    pub fn step_n(&mut self, n: usize) {
+        for _ in 0..n {
+            self.step();
+        }
+    }
+
+    /// Clear the trail maps, reseed the RNG from settings and respawn agents
+    /// as configured, so the same seed and settings replay the same run,
+    /// food sources and walls are kept
+    pub fn reset(&mut self) {
+        self.sync_size();
+        self.rng = ChaCha8Rng::seed_from_u64(self.settings.seed);
+        with_state!(&mut self.host, state => {
+            state.trail_maps.clear();
+            state.trail_backs.clear();
+        });
+        self.spawn(self.settings.spawn);
+        self.step_count = 0;
+    }
+
+    /// Replace all agents with a new placement, trail maps are kept
+    pub fn spawn(&mut self, spawn: Spawn) {
+        self.sync_size();
+        let settings = &self.settings;
+        let obstacle_map = &self.obstacle_map;
+        let rng = &mut self.rng;
+        let agents = (0..MAX_AGENT_N as usize)
+            .map(|i| spawn_agent(spawn, settings.species_of(i), settings, obstacle_map, rng));
+        with_state!(&mut self.host, state => {
+            state.agents = agents.map(Agent::from_f64).collect();
+        });
+        #[cfg(feature = "opencl")]
+        {
+            self.agents_stale = false;
+        }
+        self.placed_n = 0;
+        self.sync_species();
+        self.sync_exclusive();
+        self.host_changed();
+    }
+
+    /// Add a food source of `food_radius` and `food_strength` centered on `(x, y)`
+    pub fn add_food(&mut self, x: f64, y: f64) {
+        self.sync_size();
+        let strength = self.settings.food_strength;
+        for cell in layer::disc(x, y, self.settings.food_radius, &self.settings) {
+            self.nutrient_map[cell] = self.nutrient_map[cell].max(strength);
+        }
+        self.host_changed();
+    }
+
+    pub fn clear_food(&mut self) {
+        self.nutrient_map.fill(0.0);
+        self.host_changed();
+    }
+
+    /// Add or erase walls in a disc of `wall_radius` centered on `(x, y)`
+    pub fn paint_wall(&mut self, x: f64, y: f64, wall: bool) {
+        self.sync_size();
+        for cell in layer::disc(x, y, self.settings.wall_radius, &self.settings) {
+            self.obstacle_map[cell] = wall;
+        }
+        self.host_changed();
+    }
+
+    pub fn clear_walls(&mut self) {
+        self.obstacle_map.fill(false);
+        self.host_changed();
+    }
+
+    /// Replace the walls with an image stretched over the world, dark cells
+    /// being walls
+    pub fn load_walls(&mut self, path: &Path) -> Result<(), image::ImageError> {
+        self.sync_size();
+        self.obstacle_map = layer::load_grey(path, &self.settings)?
+            .into_iter()
+            .map(|value| value < 0.5)
+            .collect();
+        self.host_changed();
+        Ok(())
+    }
+
+    /// Replace the nutrients with a greyscale image stretched over the world,
+    /// white emitting `food_strength` per step
+    pub fn load_food(&mut self, path: &Path) -> Result<(), image::ImageError> {
+        self.sync_size();
+        let strength = self.settings.food_strength;
+        self.nutrient_map = layer::load_grey(path, &self.settings)?
+            .into_iter()
+            .map(|value| value * strength)
+            .collect();
+        self.host_changed();
+        Ok(())
+    }
+
+    /// Change the world dimensions, clamped to the supported range. Trail
+    /// channels, nutrients and walls are stretched over the new world when
+    /// `resample` is set, otherwise cropped or padded from the top left
+    /// corner. Agents are scaled along when resampling, those left outside
+    /// are respawned
+    pub fn resize(&mut self, size_x: u32, size_y: u32, resample: bool) {
+        let from = (self.size_x, self.size_y);
+        let to = (size_x.clamp(1, MAX_SIZE_X), size_y.clamp(1, MAX_SIZE_Y));
+        self.settings.size_x = to.0;
+        self.settings.size_y = to.1;
+        if from == to {
+            return;
+        }
+        debug!("Resize {}x{} to {}x{}", from.0, from.1, to.0, to.1);
+        self.sync_agents();
+
+        self.nutrient_map = layer::resize(&self.nutrient_map, from, to, resample);
+        self.obstacle_map = layer::resize(&self.obstacle_map, from, to, resample);
+        (self.size_x, self.size_y) = to;
+
+        let (scale_x, scale_y) = (to.0 as f64 / from.0 as f64, to.1 as f64 / from.1 as f64);
+        with_state!(&mut self.host, state => {
+            for trail_map in &mut state.trail_maps {
+                *trail_map = layer::resize(trail_map, from, to, resample);
+            }
+            for trail_back in &mut state.trail_backs {
+                *trail_back = vec![Default::default(); (to.0 * to.1) as usize];
+            }
+            for agent in &mut state.agents {
+                let mut wide = agent.to_f64();
+                if resample {
+                    wide.pos_x *= scale_x;
+                    wide.pos_y *= scale_y;
+                }
+                if wide.pos_x >= to.0 as f64 || wide.pos_y >= to.1 as f64 {
+                    wide = Agent {
+                        energy: wide.energy,
+                        ..spawn_agent(
+                            self.settings.spawn,
+                            wide.species,
+                            &self.settings,
+                            &self.obstacle_map,
+                            &mut self.rng,
+                        )
+                    };
+                }
+                *agent = Agent::from_f64(wide);
+            }
+        });
+
+        self.placed_n = 0;
+
+        #[cfg(feature = "opencl")]
+        {
+            self.gpu = None;
+        }
+        self.host_changed();
+    }
+
+    /// Dimensions of the world the maps currently hold
+    pub fn size(&self) -> (u32, u32) {
+        (self.size_x, self.size_y)
+    }
+
+    /// Bring settings changed through `settings_mut` back in the supported
+    /// range and follow a change of the world dimensions, without resampling
+    fn sync_size(&mut self) {
+        self.settings.clamp_to_max();
+        if (self.settings.size_x, self.settings.size_y) != (self.size_x, self.size_y) {
+            self.resize(self.settings.size_x, self.settings.size_y, false);
+        }
+    }
+
+    /// Follow a change of `species_n`: one trail channel per species and
+    /// agents spread again over the species when their count changed
+    fn sync_species(&mut self) {
+        let species_n = self.settings.species_n as usize;
+        if self.trail_channels() == species_n {
+            return;
+        }
+        self.sync_agents();
+        let cells = (self.size_x * self.size_y) as usize;
+        with_state!(&mut self.host, state => {
+            state.trail_maps.resize_with(species_n, || vec![Default::default(); cells]);
+            state.trail_backs.resize_with(species_n, || vec![Default::default(); cells]);
+            for (i, agent) in state.agents.iter_mut().enumerate() {
+                agent.species = self.settings.species_of(i);
+            }
+        });
+        self.host_changed();
+    }
+
+    /// Follow a switch to exclusive movement or new live agents under it:
+    /// agents without a cell of their own are placed in free ones
+    fn sync_exclusive(&mut self) {
+        if self.settings.movement != MovementMode::Exclusive {
+            self.placed_n = 0;
+            return;
+        }
+        if self.settings.agent_n > self.placed_n {
+            self.sync_agents();
+            self.settings.agent_n = with_state!(&mut self.host, state => exclusive_place(
+                &mut state.agents,
+                self.placed_n as usize,
+                &self.obstacle_map,
+                &mut self.occupancy,
+                &self.settings,
+                &mut self.rng,
+            ));
+            self.host_changed();
+        }
+        self.placed_n = self.settings.agent_n;
+    }
+
+    /// Read the agents back from the device when OpenCL steps left the host
+    /// copy behind, keeping the old copy if the device fails
+    fn sync_agents(&mut self) {
+        #[cfg(feature = "opencl")]
+        if std::mem::take(&mut self.agents_stale) {
+            if let Some(gpu) = &self.gpu {
+                let read = with_state!(&mut self.host, state => {
+                    gpu.read_agents(&mut state.agents, &self.settings)
+                });
+                if let Err(e) = read {
+                    warn!("cannot read agents back from the device: {e}");
+                }
+            }
+        }
+    }
+
+    /// Mark the device copy as outdated after the host state changed
+    fn host_changed(&mut self) {
+        #[cfg(feature = "opencl")]
+        {
+            self.gpu_stale = true;
+        }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Settings to change, values out of range are clamped before the next
+    /// operation on the simulation
+    pub fn settings_mut(&mut self) -> &mut Settings {
+        &mut self.settings
+    }
+
+    /// Copy of the agents in `f64`, read back from the device first after
+    /// OpenCL steps
+    pub fn agents(&mut self) -> Agents {
+        self.sync_agents();
+        with_state!(&self.host, state => state.agents.iter().map(|agent| agent.to_f64()).collect())
+    }
+
+    /// Replace the first agents with `agents`, rounded to the precision
+    pub fn set_agents(&mut self, agents: &[Agent]) {
+        self.sync_agents();
+        with_state!(&mut self.host, state => {
+            for (agent, &new) in state.agents.iter_mut().zip(agents) {
+                *agent = Agent::from_f64(new);
+            }
+        });
+        self.placed_n = 0;
+        self.host_changed();
+    }
+
+    /// Copy of the trail channels in `f64`, one per simulated species
+    pub fn trail_maps(&self) -> Vec<TrailMap> {
+        with_state!(&self.host, state => state
+            .trail_maps
+            .iter()
+            .map(|trail_map| trail_map.iter().map(|value| value.to_f64()).collect())
+            .collect())
+    }
+
+    /// Number of trail channels, one per simulated species
+    pub fn trail_channels(&self) -> usize {
+        with_state!(&self.host, state => state.trail_maps.len())
+    }
+
+    /// Trail of one cell in the channel of `species`
+    pub fn trail(&self, species: usize, cell: usize) -> f64 {
+        with_state!(&self.host, state => state.trail_maps[species][cell].to_f64())
+    }
+
+    /// Replace the trail channels with `trail_maps`, rounded to the
+    /// precision
+    pub fn set_trail_maps(&mut self, trail_maps: &[TrailMap]) {
+        with_state!(&mut self.host, state => {
+            for (trail_map, new) in state.trail_maps.iter_mut().zip(trail_maps) {
+                for (value, &new) in trail_map.iter_mut().zip(new) {
+                    *value = Real::from_f64(new);
+                }
+            }
+        });
+        self.host_changed();
+    }
+
+    pub fn nutrient_map(&self) -> &NutrientMap {
+        &self.nutrient_map
+    }
+
+    pub fn nutrient_map_mut(&mut self) -> &mut NutrientMap {
+        self.host_changed();
+        &mut self.nutrient_map
+    }
+
+    pub fn obstacle_map(&self) -> &ObstacleMap {
+        &self.obstacle_map
+    }
+
+    pub fn obstacle_map_mut(&mut self) -> &mut ObstacleMap {
+        self.host_changed();
+        &mut self.obstacle_map
+    }
+
+    /// Number of steps run since creation or last reset
+    pub fn step_count(&self) -> u64 {
+        self.step_count
+    }
+
+    /// Phase times of the last step, the OpenCL ones only after
+    /// `set_gpu_timed(true)` and including waiting for the device after each
+    /// phase
+    pub fn timings(&self) -> &StepTimings {
+        &self.timings
+    }
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Simulation::new(Settings::default())
+    }
+}
@@ -0,0 +1,522 @@
+//! The OpenCL kernels against the CPU step functions, one phase at a time on
+//! the same inputs. Needs an OpenCL device, a CPU implementation such as PoCL
+//! is enough: `cargo test --features opencl --test gpu_equivalence`. Tests
+//! are skipped without a device, unless `SRANE_REQUIRE_OPENCL=1` as in CI
+#![cfg(feature = "opencl")]
+
+use rand::{rngs::mock::StepRng, rngs::StdRng, Rng, SeedableRng};
+use srane::{
+    config::{
+        BoundaryMode, DecayMode, DepositMode, DiffusionKernel, MovementMode, Precision, Settings,
+        Spawn, SteeringRule,
+    },
+    gpu::{self, GpuBackend, GpuDevice, Phase},
+    layer::{NutrientMap, ObstacleMap},
+    simulation::{self, Agent, Agents, TrailMap},
+    Simulation,
+};
+
+/// Share of values allowed to differ, for cells or agents sitting on a
+/// rounding edge where `f32` and `f64` can fall on either side
+const F32_MISMATCH: f64 = 0.01;
+
+/// First OpenCL device, `None` to skip the test without one, which fails it
+/// when `SRANE_REQUIRE_OPENCL=1`
+fn device() -> Option<GpuDevice> {
+    let device = gpu::devices().into_iter().next();
+    if device.is_none() {
+        assert!(
+            std::env::var("SRANE_REQUIRE_OPENCL").as_deref() != Ok("1"),
+            "no OpenCL device, required by SRANE_REQUIRE_OPENCL"
+        );
+        eprintln!("no OpenCL device, skipped");
+    }
+    device
+}
+
+/// Same inputs for both backends, on a world that is not square so a wrong
+/// stride shows up
+struct World {
+    settings: Settings,
+    agents: Agents,
+    trail_maps: Vec<TrailMap>,
+    nutrient_map: NutrientMap,
+    obstacle_map: ObstacleMap,
+}
+
+impl World {
+    fn new(boundary: BoundaryMode) -> World {
+        let mut settings = Settings {
+            size_x: 160,
+            size_y: 96,
+            agent_n: 4000,
+            species_n: 2,
+            boundary,
+            trail_decay: 1.5,
+            trail_diffuse: 0.3,
+            ..Settings::default()
+        };
+        settings.species[1].agent_speed = 2.5;
+        settings.species[1].sensor_size = 2;
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let cells = (settings.size_x * settings.size_y) as usize;
+        let agents = (0..settings.agent_n as usize)
+            .map(|i| Agent::spawn(Spawn::Random, settings.species_of(i), &settings, &mut rng))
+            .collect();
+        // Patches of empty trail so some agents see ties
+        let trail_maps = (0..settings.species_n)
+            .map(|_| {
+                (0..cells)
+                    .map(|cell| {
+                        if cell % 7 < 2 {
+                            0.0
+                        } else {
+                            rng.gen::<f64>() * 255.0
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        let nutrient_map = (0..cells)
+            .map(|_| if rng.gen::<f64>() < 0.1 { 20.0 } else { 0.0 })
+            .collect();
+        // A block of wall and a few scattered cells
+        let obstacle_map = (0..cells)
+            .map(|cell| {
+                let (x, y) = (cell as u32 % settings.size_x, cell as u32 / settings.size_x);
+                (40..60).contains(&x) && (30..50).contains(&y) || rng.gen::<f64>() < 0.02
+            })
+            .collect();
+
+        World {
+            settings,
+            agents,
+            trail_maps,
+            nutrient_map,
+            obstacle_map,
+        }
+    }
+
+    /// Device holding this world, `None` when no OpenCL device is available
+    fn upload(&self, precision: Precision) -> Option<GpuBackend> {
+        let device = device()?;
+        if precision == Precision::Double && !device.supports_double() {
+            eprintln!("{} has no double precision, skipped", device.name);
+            return None;
+        }
+        let gpu = GpuBackend::new(Some(&device), precision, &self.settings)
+            .expect("cannot build the OpenCL kernels");
+        gpu.upload(
+            &self.agents,
+            &self.trail_maps,
+            &self.nutrient_map,
+            &self.obstacle_map,
+            0,
+        )
+        .expect("cannot upload the world");
+        Some(gpu)
+    }
+
+    fn run(&self, gpu: &GpuBackend, phase: Phase) -> (Agents, Vec<TrailMap>) {
+        gpu.run_phase(phase, &self.settings).expect("phase failed");
+        let mut agents = self.agents.clone();
+        let mut trail_maps = self.trail_maps.clone();
+        gpu.read_agents(&mut agents, &self.settings)
+            .expect("cannot read agents");
+        gpu.read_trails(&mut trail_maps)
+            .expect("cannot read trails");
+        (agents, trail_maps)
+    }
+}
+
+/// Tolerance on values up to a few hundred and share of values allowed
+/// beyond it
+fn tolerance(precision: Precision) -> (f64, f64) {
+    match precision {
+        Precision::Single => (1e-2, F32_MISMATCH),
+        Precision::Double => (1e-9, 0.0),
+    }
+}
+
+fn assert_close(what: &str, cpu: &[f64], gpu: &[f64], precision: Precision) {
+    let (tolerance, allowed) = tolerance(precision);
+    assert_eq!(cpu.len(), gpu.len(), "{what}: lengths differ");
+    let mismatches: Vec<_> = cpu
+        .iter()
+        .zip(gpu)
+        .enumerate()
+        .filter(|(_, (cpu, gpu))| (*cpu - *gpu).abs() > tolerance)
+        .collect();
+    assert!(
+        mismatches.len() as f64 <= allowed * cpu.len() as f64,
+        "{what} ({precision}): {} of {} differ, first {:?}",
+        mismatches.len(),
+        cpu.len(),
+        &mismatches[..mismatches.len().min(5)]
+    );
+}
+
+fn positions(agents: &[Agent]) -> Vec<f64> {
+    agents
+        .iter()
+        .flat_map(|agent| {
+            let (x, y) = agent.position();
+            [x, y]
+        })
+        .collect()
+}
+
+fn angles(agents: &[Agent]) -> Vec<f64> {
+    agents.iter().map(Agent::angle).collect()
+}
+
+fn each_precision(test: impl Fn(Precision)) {
+    for precision in Precision::ALL {
+        test(precision);
+    }
+}
+
+/// Every deposit mode, with a cap some cells reach and others do not,
+/// snapped and bilinear
+#[test]
+fn deposit() {
+    each_precision(|precision| {
+        for bilinear in [false, true] {
+            for mode in DepositMode::ALL {
+                let mut world = World::new(BoundaryMode::Wrap);
+                world.settings.deposit = mode;
+                world.settings.deposit_cap = 400.0;
+                world.settings.bilinear = bilinear;
+                let Some(gpu) = world.upload(precision) else {
+                    return;
+                };
+                let (_, gpu_trails) = world.run(&gpu, Phase::Deposit);
+                let mut cpu_trails = world.trail_maps.clone();
+                simulation::cpu_deposit(
+                    &world.agents,
+                    &mut cpu_trails,
+                    &mut Vec::new(),
+                    &world.settings,
+                );
+                let what = format!("deposit {mode} bilinear={bilinear}");
+                for (cpu, gpu) in cpu_trails.iter().zip(&gpu_trails) {
+                    assert_close(&what, cpu, gpu, precision);
+                }
+            }
+        }
+    });
+}
+
+#[test]
+fn inject_nutrients() {
+    each_precision(|precision| {
+        let world = World::new(BoundaryMode::Clamp);
+        let Some(gpu) = world.upload(precision) else {
+            return;
+        };
+        let (_, gpu_trails) = world.run(&gpu, Phase::Nutrients);
+        let mut cpu_trails = world.trail_maps.clone();
+        simulation::cpu_inject_nutrients(
+            &world.nutrient_map,
+            &world.obstacle_map,
+            &mut cpu_trails,
+            &world.settings,
+        );
+        for (cpu, gpu) in cpu_trails.iter().zip(&gpu_trails) {
+            assert_close("inject_nutrients", cpu, gpu, precision);
+        }
+    });
+}
+
+/// Every kernel kind under every boundary mode, and every decay mode
+#[test]
+fn diffuse_decay() {
+    each_precision(|precision| {
+        for boundary in BoundaryMode::ALL {
+            for diffusion in DiffusionKernel::defaults() {
+                let mut world = World::new(boundary);
+                world.settings.diffusion = diffusion;
+                let Some(gpu) = world.upload(precision) else {
+                    return;
+                };
+                let (_, gpu_trails) = world.run(&gpu, Phase::DiffuseDecay);
+                for (source, gpu) in world.trail_maps.iter().zip(&gpu_trails) {
+                    let mut cpu = vec![0.0; source.len()];
+                    simulation::cpu_diffuse_decay(
+                        source,
+                        &mut cpu,
+                        &world.obstacle_map,
+                        &world.settings,
+                    );
+                    let what = format!("diffuse_decay {boundary} {}", world.settings.diffusion);
+                    assert_close(&what, &cpu, gpu, precision);
+                }
+            }
+        }
+        for decay_mode in DecayMode::ALL {
+            let mut world = World::new(BoundaryMode::Clamp);
+            world.settings.decay_mode = decay_mode;
+            let Some(gpu) = world.upload(precision) else {
+                return;
+            };
+            let (_, gpu_trails) = world.run(&gpu, Phase::DiffuseDecay);
+            for (source, gpu) in world.trail_maps.iter().zip(&gpu_trails) {
+                let mut cpu = vec![0.0; source.len()];
+                simulation::cpu_diffuse_decay(
+                    source,
+                    &mut cpu,
+                    &world.obstacle_map,
+                    &world.settings,
+                );
+                assert_close(&format!("decay {decay_mode}"), &cpu, gpu, precision);
+            }
+        }
+    });
+}
+
+/// Positions are compared in every mode but respawn, angles only where no
+/// random heading is drawn
+#[test]
+fn move_agents() {
+    each_precision(|precision| {
+        for boundary in [
+            BoundaryMode::Clamp,
+            BoundaryMode::Wrap,
+            BoundaryMode::Reflect,
+        ] {
+            let world = World::new(boundary);
+            let Some(gpu) = world.upload(precision) else {
+                return;
+            };
+            let (gpu_agents, _) = world.run(&gpu, Phase::Move);
+            let mut cpu_agents = world.agents.clone();
+            simulation::cpu_move(
+                &mut cpu_agents,
+                &world.obstacle_map,
+                &mut Vec::new(),
+                &world.settings,
+                &mut StdRng::seed_from_u64(0),
+            );
+            let what = format!("move {boundary}");
+            assert_close(
+                &what,
+                &positions(&cpu_agents),
+                &positions(&gpu_agents),
+                precision,
+            );
+            if boundary == BoundaryMode::Reflect {
+                assert_close(&what, &angles(&cpu_agents), &angles(&gpu_agents), precision);
+            }
+        }
+    });
+}
+
+/// Exclusive moves depend on the order agents move in, which differs
+/// between backends, so both are checked against the rule: a cell entered
+/// during the step holds no other agent
+#[test]
+fn exclusive_move() {
+    each_precision(|precision| {
+        let mut world = World::new(BoundaryMode::Wrap);
+        world.settings.movement = MovementMode::Exclusive;
+        let Some(gpu) = world.upload(precision) else {
+            return;
+        };
+        let (gpu_agents, _) = world.run(&gpu, Phase::Move);
+        let mut cpu_agents = world.agents.clone();
+        simulation::cpu_move(
+            &mut cpu_agents,
+            &world.obstacle_map,
+            &mut Vec::new(),
+            &world.settings,
+            &mut StdRng::seed_from_u64(0),
+        );
+        let cell = |agent: &Agent| {
+            let (x, y) = agent.position();
+            x as usize + world.settings.size_x as usize * y as usize
+        };
+        let cells = (world.settings.size_x * world.settings.size_y) as usize;
+        for (backend, moved) in [("cpu", &cpu_agents), ("gpu", &gpu_agents)] {
+            let (mut entered, mut stayed) = (vec![0; cells], vec![0; cells]);
+            for (before, after) in world.agents.iter().zip(moved) {
+                if cell(before) == cell(after) {
+                    stayed[cell(after)] += 1;
+                } else {
+                    entered[cell(after)] += 1;
+                }
+            }
+            let crowded = (0..cells)
+                .filter(|&cell| entered[cell] > 1 || entered[cell] == 1 && stayed[cell] > 0)
+                .count();
+            // Single precision positions can fall in a neighbour cell
+            let allowed = tolerance(precision).1 * world.agents.len() as f64;
+            assert!(
+                crowded as f64 <= allowed,
+                "exclusive move {backend} ({precision}): {crowded} entered cells hold other agents"
+            );
+        }
+    });
+}
+
+/// Whole exclusive OpenCL steps keep one agent per cell, through respawns
+/// on the device and placements and divisions on the host
+#[test]
+fn exclusive_one_per_cell() {
+    let Some(device) = device() else {
+        return;
+    };
+    let mut simulation = Simulation::new(Settings {
+        size_x: 120,
+        size_y: 80,
+        agent_n: 2000,
+        movement: MovementMode::Exclusive,
+        boundary: BoundaryMode::Respawn,
+        spawn: Spawn::Circle,
+        spawn_radius: 20.0,
+        lifecycle: true,
+        trail_gain: 0.01,
+        ..Settings::default()
+    });
+    simulation.set_gpu_device(Some(device));
+    let (size_x, size_y) = simulation.size();
+    for _ in 0..30 {
+        simulation.step_gpu().expect("OpenCL step failed");
+        let agent_n = simulation.settings().agent_n as usize;
+        let mut occupancy = vec![0; (size_x * size_y) as usize];
+        for agent in &simulation.agents()[..agent_n] {
+            let (x, y) = agent.position();
+            occupancy[x as usize + size_x as usize * y as usize] += 1;
+        }
+        assert_eq!(occupancy.into_iter().max(), Some(1));
+    }
+}
+
+/// The random steer strength differs between backends, so the CPU runs with
+/// a constant one making each decision recognisable, and the device turn
+/// must fall in the range of the same decision
+#[test]
+fn sense_rotate() {
+    // Random steer strength of 0.75
+    const STRENGTH: u64 = 0xC000_0000_0000_0000;
+    each_precision(|precision| {
+        for (boundary, bilinear) in BoundaryMode::ALL
+            .into_iter()
+            .flat_map(|boundary| [(boundary, false), (boundary, true)])
+        {
+            let mut world = World::new(boundary);
+            world.settings.bilinear = bilinear;
+            let Some(gpu) = world.upload(precision) else {
+                return;
+            };
+            let (gpu_agents, _) = world.run(&gpu, Phase::SenseRotate);
+            let mut cpu_agents = world.agents.clone();
+            simulation::cpu_sense_rotate(
+                &world.trail_maps,
+                &world.obstacle_map,
+                &mut cpu_agents,
+                &world.settings,
+                &mut StepRng::new(STRENGTH, 0),
+            );
+
+            let mismatches = world
+                .agents
+                .iter()
+                .zip(cpu_agents.iter().zip(&gpu_agents))
+                .filter(|(agent, (cpu, gpu))| {
+                    let turn = world.settings.species[agent.species() as usize]
+                        .agent_turn
+                        .to_radians();
+                    let cpu = (cpu.angle() - agent.angle()) / turn;
+                    let gpu = (gpu.angle() - agent.angle()) / turn;
+                    let range = if cpu.abs() < 1e-6 {
+                        // Keep forward
+                        -1e-3..=1e-3
+                    } else if (cpu + 0.75).abs() < 1e-6 {
+                        // Turn right
+                        -1.0 - 1e-3..=1e-3
+                    } else if (cpu - 0.75).abs() < 1e-6 {
+                        // Turn left
+                        -1e-3..=1.0 + 1e-3
+                    } else {
+                        // Random turn
+                        -1.0 - 1e-3..=1.0 + 1e-3
+                    };
+                    !range.contains(&gpu)
+                })
+                .count();
+            let allowed = match precision {
+                Precision::Single => F32_MISMATCH,
+                Precision::Double => 0.001,
+            };
+            assert!(
+                mismatches as f64 <= allowed * world.agents.len() as f64,
+                "sense_rotate {boundary} bilinear={bilinear} ({precision}): {mismatches} of {} agents decided differently",
+                world.agents.len()
+            );
+        }
+    });
+}
+
+/// Rules other than the random one turn by one of a few amounts: the device
+/// turn must match the CPU one with a random number below or above one half,
+/// or for the softmax any of the three sensors
+#[test]
+fn steering_rules() {
+    const STRENGTHS: [u64; 2] = [0x4000_0000_0000_0000, 0xC000_0000_0000_0000];
+    each_precision(|precision| {
+        for rule in SteeringRule::defaults()
+            .into_iter()
+            .filter(|rule| *rule != SteeringRule::Random)
+        {
+            let mut world = World::new(BoundaryMode::Wrap);
+            for species in &mut world.settings.species {
+                species.steering = rule;
+            }
+            let Some(gpu) = world.upload(precision) else {
+                return;
+            };
+            let (gpu_agents, _) = world.run(&gpu, Phase::SenseRotate);
+            let cpu_runs = STRENGTHS.map(|strength| {
+                let mut cpu_agents = world.agents.clone();
+                simulation::cpu_sense_rotate(
+                    &world.trail_maps,
+                    &world.obstacle_map,
+                    &mut cpu_agents,
+                    &world.settings,
+                    &mut StepRng::new(strength, 0),
+                );
+                cpu_agents
+            });
+
+            let mismatches = world
+                .agents
+                .iter()
+                .enumerate()
+                .filter(|(i, agent)| {
+                    let turn = world.settings.species[agent.species() as usize]
+                        .agent_turn
+                        .to_radians();
+                    let turns = match rule {
+                        SteeringRule::Softmax { .. } => vec![-1.0, 0.0, 1.0],
+                        _ => cpu_runs
+                            .iter()
+                            .map(|cpu| (cpu[*i].angle() - agent.angle()) / turn)
+                            .collect(),
+                    };
+                    let gpu = (gpu_agents[*i].angle() - agent.angle()) / turn;
+                    !turns.iter().any(|cpu| (cpu - gpu).abs() <= 1e-3)
+                })
+                .count();
+            let allowed = match precision {
+                Precision::Single => F32_MISMATCH,
+                Precision::Double => 0.001,
+            };
+            assert!(
+                mismatches as f64 <= allowed * world.agents.len() as f64,
+                "steering {rule} ({precision}): {mismatches} of {} agents turned differently",
+                world.agents.len()
+            );
+        }
+    });
+}
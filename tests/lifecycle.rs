@@ -0,0 +1,70 @@
+//! Lifecycle phase on the CPU: feeding, starving and dividing agents
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use srane::{
+    config::Spawn,
+    simulation::{self, Agent},
+    Settings,
+};
+
+/// Species 0 feeds up to the division energy and species 1 starves, one
+/// agent of species 0 sits outside the world where it finds no food, and the
+/// population cap leaves one rich agent undivided
+#[test]
+fn feed_starve_divide() {
+    let mut settings = Settings {
+        size_x: 20,
+        size_y: 10,
+        agent_n: 7,
+        species_n: 2,
+        lifecycle: true,
+        move_cost: 1.0,
+        food_gain: 1.0,
+        trail_gain: 0.0,
+        death_energy: 0.0,
+        division_energy: 10.0,
+        max_agents: 6,
+        ..Settings::default()
+    };
+    settings.species[0].agent_speed = 1.0;
+    settings.species[1].agent_speed = 20.0;
+    let cells = (settings.size_x * settings.size_y) as usize;
+
+    let mut rng = ChaCha8Rng::seed_from_u64(0);
+    let mut agents: Vec<Agent> = (0..6)
+        .map(|i| Agent::spawn(Spawn::Star, i % 2, &settings, &mut rng))
+        .collect();
+    let outside = Agent::spawn(
+        Spawn::Circle,
+        0,
+        &Settings {
+            spawn_radius: 1e6,
+            ..settings.clone()
+        },
+        &mut rng,
+    );
+    let (x, y) = outside.position();
+    assert!(!(0.0..20.0).contains(&x) || !(0.0..10.0).contains(&y));
+    agents.push(outside);
+    agents.resize(10, Agent::default());
+
+    let agent_n = simulation::cpu_lifecycle(
+        &mut agents,
+        &[vec![0.0; cells], vec![0.0; cells]],
+        &vec![6.0; cells],
+        &vec![false; cells],
+        &mut Vec::new(),
+        &settings,
+        &mut rng,
+    );
+
+    // 5 + 6 - 1 reaches the division energy, 5 + 6 - 20 starves and 5 - 1
+    // outside lives on
+    assert_eq!(agent_n, 6);
+    let living = &agents[..agent_n as usize];
+    assert!(living.iter().all(|agent| agent.species() == 0));
+    let mut energies: Vec<f64> = living.iter().map(Agent::energy).collect();
+    energies.sort_by(f64::total_cmp);
+    assert_eq!(energies, [4.0, 5.0, 5.0, 5.0, 5.0, 10.0]);
+}
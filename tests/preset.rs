@@ -0,0 +1,70 @@
+//! Preset files and the settings they hold
+
+use srane::{
+    config::{MAX_SEED, MAX_SENSOR_DISTANCE, MAX_SPECIES},
+    preset::{self, Preset},
+    Settings,
+};
+
+fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("srane-{}-{name}", std::process::id()))
+}
+
+/// TOML integers are signed, seeds past them are brought back in range
+/// when saving, as are other values out of range
+#[test]
+fn largest_seed_saves() {
+    let mut settings = Settings {
+        seed: u64::MAX,
+        ..Settings::default()
+    };
+    settings.species[0].sensor_distance = 1e12;
+
+    let path = temp_path("seed.toml");
+    let preset = Preset {
+        name: "seed".to_owned(),
+        settings,
+    };
+    preset.save(&path).expect("cannot save the preset");
+    let loaded = Preset::load(&path);
+    std::fs::remove_file(&path).ok();
+    let settings = loaded.expect("cannot load the preset").settings;
+    assert_eq!(settings.seed, MAX_SEED);
+    assert_eq!(settings.species[0].sensor_distance, MAX_SENSOR_DISTANCE);
+}
+
+/// Every bundled preset comes back unchanged from a TOML file, and its
+/// agents can sense
+#[test]
+fn builtin_round_trip() {
+    for preset in preset::builtin() {
+        for species in &preset.settings.species {
+            assert!(species.sensor_size > 0, "{} senses nothing", preset.name);
+        }
+        let path = temp_path("builtin.toml");
+        preset.save(&path).expect("cannot save the preset");
+        let loaded = Preset::load(&path);
+        std::fs::remove_file(&path).ok();
+        assert_eq!(
+            loaded.expect("cannot load the preset").settings,
+            preset.settings,
+            "{} changed",
+            preset.name
+        );
+    }
+}
+
+/// Hand-edited values out of range are clamped when loading
+#[test]
+fn load_clamps() {
+    for (species_n, expected) in [(0, 1), (9, MAX_SPECIES as u8)] {
+        let path = temp_path("clamp.toml");
+        std::fs::write(&path, format!("species_n = {species_n}\nsize_x = 0\n"))
+            .expect("cannot write the preset");
+        let loaded = Preset::load(&path);
+        std::fs::remove_file(&path).ok();
+        let settings = loaded.expect("cannot load the preset").settings;
+        assert_eq!(settings.species_n, expected);
+        assert_eq!(settings.size_x, 1);
+    }
+}
@@ -0,0 +1,529 @@
+//! Whole steps on the CPU backends, on worlds small enough for debug builds,
+//! and single phases on hand placed agents
+
+use std::f64::consts::PI;
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use srane::{
+    config::{
+        BoundaryMode, DecayMode, DepositMode, DiffusionKernel, MovementMode, Precision, Spawn,
+        Species, SteeringRule, MAX_AGENT_N, MAX_SPECIES,
+    },
+    simulation::{self, Agent},
+    Settings, Simulation,
+};
+
+fn settings() -> Settings {
+    Settings {
+        size_x: 120,
+        size_y: 80,
+        agent_n: 2000,
+        species_n: 2,
+        seed: 42,
+        ..Settings::default()
+    }
+}
+
+/// Two runs with the same seed and settings end with the same agents and
+/// trails, a different seed does not
+#[test]
+fn same_seed_same_run() {
+    let run = |simulation: &mut Simulation| {
+        simulation.add_food(30.0, 20.0);
+        simulation.paint_wall(80.0, 50.0, true);
+        simulation.step_n(50);
+    };
+    let mut first = Simulation::new(settings());
+    let mut second = Simulation::new(settings());
+    run(&mut first);
+    run(&mut second);
+    assert_eq!(first.agents(), second.agents());
+    assert_eq!(first.trail_maps(), second.trail_maps());
+
+    let mut other = Simulation::new(Settings {
+        seed: 43,
+        ..settings()
+    });
+    run(&mut other);
+    assert_ne!(first.agents(), other.agents());
+}
+
+/// A reset replays the run from the start, walls are kept and would move
+/// the spawned agents so there are none here
+#[test]
+fn reset_replays() {
+    let mut simulation = Simulation::new(settings());
+    simulation.step_n(50);
+    let (agents, trail_maps) = (simulation.agents(), simulation.trail_maps());
+    simulation.reset();
+    simulation.step_n(50);
+    assert_eq!(simulation.agents(), agents);
+    assert_eq!(simulation.trail_maps(), trail_maps);
+}
+
+/// Settings out of range are clamped instead of panicking, whether given
+/// to the constructor or changed through `settings_mut`
+#[test]
+fn clamps_settings() {
+    let mut simulation = Simulation::new(Settings {
+        size_x: 0,
+        agent_n: u32::MAX,
+        species_n: 0,
+        ..settings()
+    });
+    simulation.step();
+    assert_eq!(simulation.size(), (1, 80));
+    assert_eq!(simulation.settings().agent_n, MAX_AGENT_N);
+    assert_eq!(simulation.settings().species_n, 1);
+
+    let settings = simulation.settings_mut();
+    settings.size_x = 30;
+    settings.agent_n = u32::MAX;
+    settings.species_n = u8::MAX;
+    simulation.step_parallel();
+    assert_eq!(simulation.settings().agent_n, MAX_AGENT_N);
+    assert_eq!(simulation.settings().species_n, MAX_SPECIES as u8);
+    assert_eq!(simulation.trail_maps().len(), MAX_SPECIES);
+}
+
+/// Every agent sits in a cell of the world
+fn assert_inside(simulation: &mut Simulation) {
+    let (size_x, size_y) = simulation.size();
+    let agent_n = simulation.settings().agent_n as usize;
+    for agent in &simulation.agents()[..agent_n] {
+        let (x, y) = agent.position();
+        assert!(
+            (0.0..size_x as f64).contains(&x) && (0.0..size_y as f64).contains(&y),
+            "agent outside the world at ({x}, {y})"
+        );
+    }
+}
+
+/// Agents leaving a respawn world come back inside it, whatever the spawn
+/// shape, here a circle larger than the world
+#[test]
+fn respawn_every_spawn() {
+    for spawn in [Spawn::Random, Spawn::Circle, Spawn::Star] {
+        for bilinear in [false, true] {
+            let mut simulation = Simulation::new(Settings {
+                boundary: BoundaryMode::Respawn,
+                spawn,
+                spawn_radius: 200.0,
+                bilinear,
+                ..settings()
+            });
+            simulation.settings_mut().species[0].agent_speed = 3.0;
+            assert_inside(&mut simulation);
+            for _ in 0..10 {
+                simulation.step();
+                simulation.step_parallel();
+                assert_inside(&mut simulation);
+            }
+        }
+    }
+}
+
+/// Mean trail per cell and root mean square distance of the agents to their
+/// centre
+fn trail_and_spread(simulation: &mut Simulation) -> (f64, f64) {
+    let (size_x, size_y) = simulation.size();
+    let trail = simulation.trail_maps().iter().flatten().sum::<f64>() / (size_x * size_y) as f64;
+    let agent_n = simulation.settings().agent_n as usize;
+    let agents = &simulation.agents()[..agent_n];
+    let mean = |coord: fn(&srane::simulation::Agent) -> f64| {
+        agents.iter().map(coord).sum::<f64>() / agent_n as f64
+    };
+    let (mean_x, mean_y) = (mean(|a| a.position().0), mean(|a| a.position().1));
+    let spread = agents
+        .iter()
+        .map(|agent| {
+            let (x, y) = agent.position();
+            (x - mean_x).powi(2) + (y - mean_y).powi(2)
+        })
+        .sum::<f64>()
+        / agent_n as f64;
+    (trail, spread.sqrt())
+}
+
+/// The parallel backend draws other random numbers, over a few seeds it
+/// ends with as much trail and as spread agents as the serial one
+#[test]
+fn parallel_like_serial() {
+    const SEEDS: u64 = 3;
+    for (deposit, bilinear) in [
+        (DepositMode::Overwrite, false),
+        (DepositMode::Additive, true),
+    ] {
+        let (mut serial, mut parallel) = ((0.0, 0.0), (0.0, 0.0));
+        for seed in 0..SEEDS {
+            let settings = Settings {
+                seed,
+                deposit,
+                bilinear,
+                ..settings()
+            };
+            let (mut first, mut second) =
+                (Simulation::new(settings.clone()), Simulation::new(settings));
+            for _ in 0..60 {
+                first.step();
+                second.step_parallel();
+            }
+            let (trail, spread) = trail_and_spread(&mut first);
+            serial = (serial.0 + trail, serial.1 + spread);
+            let (trail, spread) = trail_and_spread(&mut second);
+            parallel = (parallel.0 + trail, parallel.1 + spread);
+        }
+        let differs = |serial: f64, parallel: f64| (parallel - serial).abs() / serial;
+        assert!(
+            differs(serial.0, parallel.0) < 0.05,
+            "{deposit:?}: mean trail {serial:?} serial, {parallel:?} parallel"
+        );
+        assert!(
+            differs(serial.1, parallel.1) < 0.08,
+            "{deposit:?}: agent spread {serial:?} serial, {parallel:?} parallel"
+        );
+    }
+}
+
+/// Parallel runs don't depend on the number of threads
+#[test]
+fn parallel_thread_count() {
+    let run = |threads: usize| {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .expect("cannot build the thread pool");
+        let mut simulation = Simulation::new(Settings {
+            deposit: DepositMode::Additive,
+            bilinear: true,
+            ..settings()
+        });
+        pool.install(|| {
+            for _ in 0..20 {
+                simulation.step_parallel();
+            }
+        });
+        (simulation.agents(), simulation.trail_maps())
+    };
+    assert!(
+        run(1) == run(3),
+        "parallel run changed with the thread count"
+    );
+}
+
+/// Single precision keeps agents and trails in `f32` on the CPU backends and
+/// still replays, switching back to double precision keeps the state
+#[test]
+fn single_precision() {
+    let run = |precision: Precision| {
+        let mut simulation = Simulation::new(settings());
+        simulation.set_precision(precision);
+        simulation.add_food(30.0, 20.0);
+        simulation.step_n(20);
+        simulation
+    };
+    let (mut first, mut second) = (run(Precision::Single), run(Precision::Single));
+    let (agents, trail_maps) = (first.agents(), first.trail_maps());
+    assert_eq!(second.agents(), agents);
+    assert_eq!(second.trail_maps(), trail_maps);
+    assert_ne!(run(Precision::Double).agents(), agents);
+
+    let single = |value: f64| value as f32 as f64 == value;
+    for agent in &agents {
+        let (x, y) = agent.position();
+        assert!(single(x) && single(y) && single(agent.angle()), "{agent:?}");
+    }
+    assert!(trail_maps.iter().flatten().all(|&value| single(value)));
+    assert!(trail_maps.iter().flatten().any(|&value| value > 0.0));
+
+    first.set_precision(Precision::Double);
+    assert_eq!(first.precision(), Precision::Double);
+    assert_eq!(first.agents(), agents);
+    assert_eq!(first.trail_maps(), trail_maps);
+}
+
+/// Largest number of live agents sharing a cell
+fn max_occupancy(simulation: &mut Simulation) -> u32 {
+    let (size_x, size_y) = simulation.size();
+    let agent_n = simulation.settings().agent_n as usize;
+    let mut occupancy = vec![0; (size_x * size_y) as usize];
+    for agent in &simulation.agents()[..agent_n] {
+        let (x, y) = agent.position();
+        occupancy[x as usize + size_x as usize * y as usize] += 1;
+    }
+    occupancy.into_iter().max().unwrap_or(0)
+}
+
+/// Exclusive movement keeps one agent per cell from the spawn on, through
+/// respawns and divisions, agents spawned without a free cell are dropped.
+/// Single precision positions are checked in the cell they are rounded to
+#[test]
+fn exclusive_one_per_cell() {
+    for (parallel, precision) in [false, true].into_iter().zip(Precision::ALL) {
+        let mut simulation = Simulation::new(Settings {
+            movement: MovementMode::Exclusive,
+            boundary: BoundaryMode::Respawn,
+            spawn: Spawn::Circle,
+            spawn_radius: 20.0,
+            lifecycle: true,
+            trail_gain: 0.01,
+            ..settings()
+        });
+        simulation.set_precision(precision);
+        let spawned = simulation.settings().agent_n;
+        assert!(
+            spawned < settings().agent_n,
+            "crowded spawn kept all agents"
+        );
+        assert_eq!(max_occupancy(&mut simulation), 1);
+        for _ in 0..30 {
+            if parallel {
+                simulation.step_parallel();
+            } else {
+                simulation.step();
+            }
+            assert_eq!(max_occupancy(&mut simulation), 1);
+        }
+        assert!(simulation.settings().agent_n > spawned, "no agent divided");
+    }
+}
+
+/// 20×10 world of one `species`, for single phases on hand placed agents
+fn small(species: Species, agent_n: u32) -> Settings {
+    Settings {
+        size_x: 20,
+        size_y: 10,
+        agent_n,
+        species_n: 1,
+        ..Settings::single(species)
+    }
+}
+
+/// An agent crossing the right edge comes back as its boundary mode says
+#[test]
+fn boundary_modes() {
+    for boundary in BoundaryMode::ALL {
+        let settings = Settings {
+            boundary,
+            ..small(
+                Species {
+                    agent_speed: 2.0,
+                    ..Species::default()
+                },
+                1,
+            )
+        };
+        let mut agents = vec![Agent::at(19.5, 5.5, 0.0, 0)];
+        simulation::cpu_move(
+            &mut agents,
+            &vec![false; 200],
+            &mut Vec::new(),
+            &settings,
+            &mut ChaCha8Rng::seed_from_u64(0),
+        );
+        let (x, y) = agents[0].position();
+        match boundary {
+            BoundaryMode::Clamp => assert_eq!((x, y), (19.0, 5.5)),
+            BoundaryMode::Wrap => {
+                assert_eq!((x, y), (1.5, 5.5));
+                assert_eq!(agents[0].angle(), 0.0);
+            }
+            BoundaryMode::Reflect => {
+                assert_eq!((x, y), (18.5, 5.5));
+                assert_eq!(agents[0].angle(), PI);
+            }
+            BoundaryMode::Respawn => {
+                assert!((0.0..20.0).contains(&x) && (0.0..10.0).contains(&y));
+            }
+        }
+    }
+}
+
+/// Every diffusion kernel spreads hot cells without losing trail on a
+/// wrapping world when nothing decays
+#[test]
+fn diffusion_keeps_mass() {
+    let kernels = DiffusionKernel::defaults().into_iter().chain([
+        DiffusionKernel::Gaussian { sigma: 1.5 },
+        DiffusionKernel::Anisotropic {
+            sigma_along: 2.0,
+            sigma_across: 0.5,
+            angle: 30.0,
+        },
+    ]);
+    for diffusion in kernels {
+        let settings = Settings {
+            boundary: BoundaryMode::Wrap,
+            diffusion: diffusion.clone(),
+            trail_diffuse: 1.0,
+            trail_decay: 0.0,
+            ..small(Species::default(), 0)
+        };
+        let mut source = vec![0.0; 200];
+        source[10 + 20 * 5] = 100.0;
+        source[3] = 50.0;
+        let mut target = vec![0.0; 200];
+        simulation::cpu_diffuse_decay(&source, &mut target, &vec![false; 200], &settings);
+        let mass: f64 = target.iter().sum();
+        assert!(
+            (mass - 150.0).abs() < 1e-9,
+            "{diffusion:?} ends with {mass}"
+        );
+        assert!(
+            target[10 + 20 * 5] < 100.0 && target[11 + 20 * 5] > 0.0,
+            "{diffusion:?} did not spread"
+        );
+    }
+}
+
+/// One step of each decay mode on a uniform trail, which diffusion leaves
+/// unchanged
+#[test]
+fn decay_modes() {
+    for decay_mode in DecayMode::ALL {
+        let settings = Settings {
+            decay_mode,
+            trail_decay: 2.5,
+            trail_decay_rate: 0.1,
+            trail_half_life: 2.0,
+            ..small(Species::default(), 0)
+        };
+        let mut target = vec![0.0; 200];
+        simulation::cpu_diffuse_decay(&vec![100.0; 200], &mut target, &vec![false; 200], &settings);
+        let expected = match decay_mode {
+            DecayMode::Linear => 97.5,
+            DecayMode::Multiplicative => 90.0,
+            DecayMode::Exponential => 100.0 / 2.0_f64.sqrt(),
+            DecayMode::Combined => 87.5,
+        };
+        for value in target {
+            assert!((value - expected).abs() < 1e-9, "{decay_mode}: {value}");
+        }
+    }
+}
+
+/// Two agents in one cell depositing 7 on a trail of `start`, with the
+/// serial and parallel deposit
+fn deposit_twice(deposit: DepositMode, bilinear: bool, start: f64) -> [Vec<f64>; 2] {
+    let settings = Settings {
+        deposit,
+        deposit_cap: 20.0,
+        bilinear,
+        ..small(
+            Species {
+                trail_weight: 7.0,
+                ..Species::default()
+            },
+            2,
+        )
+    };
+    let agents = vec![Agent::at(5.5, 5.5, 0.0, 0); 2];
+    [simulation::cpu_deposit, simulation::par_deposit].map(|deposit| {
+        let mut trail_maps = vec![vec![start; 200]];
+        deposit(&agents, &mut trail_maps, &mut Vec::new(), &settings);
+        trail_maps.remove(0)
+    })
+}
+
+/// Overwrite sets the trail, additive sums both agents, capped stops at the
+/// cap and max keeps the larger of trail and weight
+#[test]
+fn deposit_modes() {
+    for deposit in DepositMode::ALL {
+        for (start, expected) in match deposit {
+            DepositMode::Overwrite => [(10.0, 7.0), (3.0, 7.0)],
+            DepositMode::Additive => [(10.0, 24.0), (3.0, 17.0)],
+            DepositMode::Capped => [(10.0, 20.0), (3.0, 17.0)],
+            DepositMode::Max => [(10.0, 10.0), (3.0, 7.0)],
+        } {
+            for trail in deposit_twice(deposit, false, start) {
+                assert_eq!(trail[5 + 20 * 5], expected, "{deposit} on {start}");
+                assert_eq!(trail[6 + 20 * 5], start, "{deposit} beside the agents");
+            }
+        }
+    }
+}
+
+/// A bilinear agent off the cell centres spreads its whole weight over the
+/// four cells it overlaps, by how much it overlaps each
+#[test]
+fn bilinear_splat() {
+    let settings = Settings {
+        deposit: DepositMode::Additive,
+        bilinear: true,
+        ..small(
+            Species {
+                trail_weight: 8.0,
+                ..Species::default()
+            },
+            1,
+        )
+    };
+    let agents = vec![Agent::at(5.25, 5.75, 0.0, 0)];
+    for deposit in [simulation::cpu_deposit, simulation::par_deposit] {
+        let mut trail_maps = vec![vec![0.0; 200]];
+        deposit(&agents, &mut trail_maps, &mut Vec::new(), &settings);
+        let trail = &trail_maps[0];
+        assert_eq!(trail.iter().sum::<f64>(), 8.0);
+        let cell = |x: usize, y: usize| trail[x + 20 * y];
+        assert_eq!(
+            [cell(4, 5), cell(5, 5), cell(4, 6), cell(5, 6)],
+            [1.5, 4.5, 0.5, 1.5]
+        );
+    }
+}
+
+/// Trail only under the left sensor: every rule turns left, by the whole
+/// `agent_turn` but for the random strength one
+#[test]
+fn steering_rules() {
+    for steering in [
+        SteeringRule::Random,
+        SteeringRule::Fixed,
+        SteeringRule::Gradient { gain: 1.0 },
+        SteeringRule::Softmax { temperature: 0.1 },
+        SteeringRule::Fan { sensors: 3 },
+    ] {
+        let settings = Settings {
+            size_x: 30,
+            size_y: 30,
+            ..small(
+                Species {
+                    agent_turn: 30.0,
+                    sensor_angle: 45.0,
+                    sensor_distance: 5.0,
+                    sensor_size: 1,
+                    steering,
+                    ..Species::default()
+                },
+                1,
+            )
+        };
+        // The left sensor at 45° sits around (14, 14)
+        let trail_maps = vec![(0..900)
+            .map(|cell| {
+                let (x, y) = (cell % 30, cell / 30);
+                if (11..18).contains(&x) && (12..18).contains(&y) {
+                    100.0
+                } else {
+                    0.0
+                }
+            })
+            .collect()];
+        let mut agents = vec![Agent::at(10.5, 10.5, 0.0, 0)];
+        simulation::cpu_sense_rotate(
+            &trail_maps,
+            &vec![false; 900],
+            &mut agents,
+            &settings,
+            &mut ChaCha8Rng::seed_from_u64(0),
+        );
+        let turn = agents[0].angle().to_degrees();
+        if steering == SteeringRule::Random {
+            assert!(turn > 0.0 && turn <= 30.0, "{steering} turned {turn}");
+        } else {
+            assert!((turn - 30.0).abs() < 1e-9, "{steering} turned {turn}");
+        }
+    }
+}